@@ -0,0 +1,352 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fileindex;
+use crate::manifest::Manifest;
+
+/// What a cache entry holds. Markers record that a task succeeded for a
+/// given input hash; artifacts are a tar archive of the task's outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Marker,
+    Artifact,
+}
+
+impl EntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::Marker => "markers",
+            EntryKind::Artifact => "artifacts",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "markers" => Some(EntryKind::Marker),
+            "artifacts" => Some(EntryKind::Artifact),
+            _ => None,
+        }
+    }
+}
+
+/// Cache keys are task hashes; anything else is rejected before it reaches a
+/// URL or a file path
+pub fn is_valid_key(key: &str) -> bool {
+    key.len() == 64 && key.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Storage shared between machines, consulted when the local cache misses
+pub trait CacheBackend: Send + Sync {
+    /// Short description for progress and error messages
+    fn describe(&self) -> String;
+
+    /// Whether results may be uploaded to this backend
+    fn read_only(&self) -> bool;
+
+    /// Open an entry for reading, `None` if the backend doesn't have it
+    fn get(&self, kind: EntryKind, key: &str) -> Result<Option<Box<dyn Read + Send>>>;
+
+    /// Store an entry of `len` bytes read from `data`
+    fn put(&self, kind: EntryKind, key: &str, data: &mut dyn Read, len: u64) -> Result<()>;
+}
+
+/// A shared cache backend from the top-level `cache.backends` list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendConfig {
+    Http {
+        url: String,
+        /// Only download results, never upload them
+        #[serde(default)]
+        read_only: bool,
+        /// Environment variable holding a bearer token sent with every request
+        #[serde(default)]
+        token_env: Option<String>,
+    },
+    /// A directory shared between machines, such as an NFS mount
+    Directory {
+        /// Relative to the `.rush` file; `~` is the home directory
+        path: String,
+        #[serde(default)]
+        read_only: bool,
+    },
+}
+
+impl BackendConfig {
+    /// Create the backend. `force_read_only` keeps even backends configured
+    /// as writable from receiving uploads.
+    pub fn build(&self, base_dir: &Path, force_read_only: bool) -> Result<Box<dyn CacheBackend>> {
+        match self {
+            BackendConfig::Http {
+                url,
+                read_only,
+                token_env,
+            } => {
+                let token = match token_env {
+                    Some(name) => Some(std::env::var(name).with_context(|| {
+                        format!("Cache backend {url} needs a token in ${name}, which is not set")
+                    })?),
+                    None => None,
+                };
+                Ok(Box::new(HttpBackend::new(
+                    url,
+                    *read_only || force_read_only,
+                    token,
+                )))
+            }
+            BackendConfig::Directory { path, read_only } => {
+                let path = match path.strip_prefix("~/") {
+                    Some(rest) => home_dir()?.join(rest),
+                    None => base_dir.join(path),
+                };
+                Ok(Box::new(DirectoryBackend::new(
+                    path,
+                    *read_only || force_read_only,
+                )))
+            }
+        }
+    }
+}
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Cache server speaking plain HTTP: `GET` and `PUT` on
+/// `<url>/markers/<hash>` and `<url>/artifacts/<hash>`, with 404 for a miss
+pub struct HttpBackend {
+    base_url: String,
+    read_only: bool,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl HttpBackend {
+    pub fn new(url: &str, read_only: bool, token: Option<String>) -> Self {
+        Self {
+            base_url: url.trim_end_matches('/').to_string(),
+            read_only,
+            token,
+            agent: ureq::AgentBuilder::new().timeout(HTTP_TIMEOUT).build(),
+        }
+    }
+
+    fn request(&self, method: &str, kind: EntryKind, key: &str) -> Result<ureq::Request> {
+        if !is_valid_key(key) {
+            bail!("Invalid cache key '{}'", key);
+        }
+
+        let url = format!("{}/{}/{}", self.base_url, kind.as_str(), key);
+        let request = self.agent.request(method, &url);
+        Ok(match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+            None => request,
+        })
+    }
+}
+
+impl CacheBackend for HttpBackend {
+    fn describe(&self) -> String {
+        self.base_url.clone()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn get(&self, kind: EntryKind, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        match self.request("GET", kind, key)?.call() {
+            Ok(response) => Ok(Some(response.into_reader())),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e).context("Cache request failed"),
+        }
+    }
+
+    fn put(&self, kind: EntryKind, key: &str, data: &mut dyn Read, len: u64) -> Result<()> {
+        if self.read_only {
+            bail!("Cache backend {} is read-only", self.base_url);
+        }
+
+        // With a length set the body is streamed as is rather than chunked
+        self.request("PUT", kind, key)?
+            .set("Content-Length", &len.to_string())
+            .send(data)
+            .context("Cache upload failed")?;
+        Ok(())
+    }
+}
+
+/// Cache stored as `<path>/markers/<hash>` and `<path>/artifacts/<hash>`.
+///
+/// Entries are written to a temporary file in the same directory and renamed
+/// into place, so any number of machines can read and write concurrently
+/// without locks and never see a partial entry.
+pub struct DirectoryBackend {
+    path: PathBuf,
+    read_only: bool,
+}
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl DirectoryBackend {
+    pub fn new(path: impl Into<PathBuf>, read_only: bool) -> Self {
+        Self {
+            path: path.into(),
+            read_only,
+        }
+    }
+
+    fn entry_path(&self, kind: EntryKind, key: &str) -> Result<PathBuf> {
+        if !is_valid_key(key) {
+            bail!("Invalid cache key '{}'", key);
+        }
+        Ok(self.path.join(kind.as_str()).join(key))
+    }
+
+    /// Open a stored entry, `None` if there is none
+    pub fn open(&self, kind: EntryKind, key: &str) -> Result<Option<File>> {
+        let path = self.entry_path(kind, key)?;
+        match File::open(&path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Store an entry, whatever its length. A failed read leaves nothing behind.
+    pub fn store(&self, kind: EntryKind, key: &str, data: &mut dyn Read) -> Result<()> {
+        let path = self.entry_path(kind, key)?;
+        write_atomic_with(&path, |file| {
+            io::copy(data, file).with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(())
+        })
+    }
+}
+
+impl DirectoryBackend {
+    /// Check every marker and the artifact it refers to, returning the marker
+    /// of each damaged entry and what is wrong with it. With `remove`, damaged
+    /// entries are deleted so they are rebuilt on the next run.
+    pub fn verify(&self, remove: bool) -> Result<Vec<(PathBuf, String)>> {
+        let mut damaged = Vec::new();
+        let markers = self.path.join(EntryKind::Marker.as_str());
+        let dir = match fs::read_dir(&markers) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(damaged),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", markers.display()))
+            }
+        };
+
+        for file in dir {
+            let path = file?.path();
+            let Some(key) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if !is_valid_key(key) {
+                continue;
+            }
+
+            let artifact = self.entry_path(EntryKind::Artifact, key)?;
+            let problem = match Manifest::parse(&fs::read(&path)?) {
+                Err(e) => format!("{e:#}"),
+                Ok(manifest) => match (manifest.artifact, fileindex::hash_file(&artifact)) {
+                    (None, _) => continue,
+                    (Some(expected), Ok(digest)) if digest == expected => continue,
+                    (Some(_), Ok(_)) => "output archive checksum mismatch".to_string(),
+                    (Some(_), Err(_)) => "output archive is missing".to_string(),
+                },
+            };
+
+            if remove {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                let _ = fs::remove_file(&artifact);
+            }
+            damaged.push((path, problem));
+        }
+
+        Ok(damaged)
+    }
+}
+
+impl CacheBackend for DirectoryBackend {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn get(&self, kind: EntryKind, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        Ok(self
+            .open(kind, key)?
+            .map(|file| Box::new(file) as Box<dyn Read + Send>))
+    }
+
+    fn put(&self, kind: EntryKind, key: &str, data: &mut dyn Read, _len: u64) -> Result<()> {
+        if self.read_only {
+            bail!("Cache backend {} is read-only", self.path.display());
+        }
+
+        self.store(kind, key, data)
+    }
+}
+
+/// Write to a temporary file next to `path` and rename it into place, so
+/// readers see either the old content or the new, never a partial write
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    write_atomic_with(path, |file| {
+        file.write_all(data)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    })
+}
+
+/// Like [`write_atomic`], with the content written by `write`. If it fails,
+/// the temporary file is removed and `path` is left untouched.
+pub fn write_atomic_with(path: &Path, write: impl FnOnce(&mut File) -> Result<()>) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+
+    // Unique across processes and machines sharing the directory
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(
+        ".tmp.{}.{}.{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        nanos
+    ));
+    let temp_path = path.with_file_name(temp_name);
+    let mut file = File::create(&temp_path)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    let written = write(&mut file).and_then(|()| {
+        file.flush()
+            .with_context(|| format!("Failed to write {}", path.display()))
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&temp_path, path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e).with_context(|| format!("Failed to write {}", path.display()));
+    }
+    Ok(())
+}
+
+fn home_dir() -> Result<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .context("Cannot expand ~ without HOME set")
+}
@@ -0,0 +1,321 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::backend;
+use crate::fileindex;
+use crate::manifest;
+
+/// Default zstd level for stored outputs; fast, with most of the size win
+pub const DEFAULT_COMPRESSION: i32 = 3;
+
+const COMPRESSED_SUFFIX: &str = ".zst";
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How a restored output file is put back into the workspace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RestoreMode {
+    /// Write a fresh copy of the blob
+    #[default]
+    Copy,
+    /// Hard-link uncompressed blobs into place, copying compressed ones
+    Hardlink,
+}
+
+/// Content-addressed store of output files and run logs in `.rush-cache/blobs`.
+///
+/// Each distinct file content is stored once, as `<aa>/<sha256>` or, when
+/// compressed, `<aa>/<sha256>.zst`, however many tasks or entries produce it.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+    /// zstd level, 0 to store blobs uncompressed
+    compression: i32,
+    restore: RestoreMode,
+}
+
+impl BlobStore {
+    pub fn new(dir: impl Into<PathBuf>, compression: i32, restore: RestoreMode) -> Self {
+        Self {
+            dir: dir.into(),
+            compression,
+            restore,
+        }
+    }
+
+    /// Store a file's content, returning its digest. Content that is already
+    /// stored is not written again.
+    pub fn put(&self, file: &Path) -> Result<String> {
+        let digest = fileindex::hash_file(file)?;
+        if self.find(&digest).is_some() {
+            return Ok(digest);
+        }
+
+        let source =
+            File::open(file).with_context(|| format!("Failed to read {}", file.display()))?;
+        self.write(&digest, &mut BufReader::new(source))?;
+        Ok(digest)
+    }
+
+    /// Store content held in memory, returning its digest
+    pub fn put_bytes(&self, data: &[u8]) -> Result<String> {
+        let digest = manifest::sha256_hex(data);
+        if self.find(&digest).is_none() {
+            self.write(&digest, &mut &data[..])?;
+        }
+        Ok(digest)
+    }
+
+    fn write(&self, digest: &str, source: &mut dyn Read) -> Result<()> {
+        if self.compression > 0 {
+            backend::write_atomic_with(&self.blob_path(digest, true), |file| {
+                zstd::stream::copy_encode(source, file, self.compression)
+                    .with_context(|| format!("Failed to compress blob {}", short(digest)))
+            })
+        } else {
+            backend::write_atomic_with(&self.blob_path(digest, false), |file| {
+                io::copy(source, file)
+                    .with_context(|| format!("Failed to store blob {}", short(digest)))?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Read a stored blob back, checking its content. A corrupted blob is
+    /// removed so the next run stores the content again.
+    pub fn get(&self, digest: &str) -> Result<Vec<u8>> {
+        let Some(blob) = self.find(digest) else {
+            bail!("blob {} is missing", short(digest));
+        };
+        let mut data = Vec::new();
+        if !copy_checked(&blob, digest, &mut data)? {
+            let _ = fs::remove_file(&blob);
+            bail!("blob {} is corrupted", short(digest));
+        }
+        Ok(data)
+    }
+
+    /// Put a stored file back at `dest` with the given Unix permission bits
+    pub fn restore(&self, digest: &str, dest: &Path, mode: u32) -> Result<()> {
+        let Some(blob) = self.find(digest) else {
+            bail!("blob {} is missing", short(digest));
+        };
+        if let Some(parent) = dest.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+
+        // A link shares the blob's permissions, so it only fits the same mode
+        let compressed = is_compressed(&blob);
+        if !compressed && self.restore == RestoreMode::Hardlink && file_mode(&blob)? == mode {
+            if fileindex::hash_file(&blob)? != digest {
+                let _ = fs::remove_file(&blob);
+                bail!("blob {} is corrupted", short(digest));
+            }
+            let _ = fs::remove_file(dest);
+            if fs::hard_link(&blob, dest).is_ok() {
+                return Ok(());
+            }
+        }
+
+        // Write next to the destination and rename, so a failed restore never
+        // leaves a truncated output behind
+        backend::write_atomic_with(dest, |file| {
+            if !copy_checked(&blob, digest, file)? {
+                let _ = fs::remove_file(&blob);
+                bail!("blob {} is corrupted", short(digest));
+            }
+            Ok(())
+        })?;
+        set_mode(dest, mode)
+    }
+
+    /// Replace a restored output that is still hard-linked to a blob with a
+    /// copy, so a task writing into it in place can't change the stored
+    /// content
+    pub fn detach(&self, file: &Path) -> Result<()> {
+        if self.restore != RestoreMode::Hardlink || !is_hard_linked(file)? {
+            return Ok(());
+        }
+        let mut source =
+            File::open(file).with_context(|| format!("Failed to read {}", file.display()))?;
+        let mode = file_mode(file)?;
+        backend::write_atomic_with(file, |copy| {
+            io::copy(&mut source, copy)
+                .with_context(|| format!("Failed to copy {}", file.display()))?;
+            Ok(())
+        })?;
+        set_mode(file, mode)
+    }
+
+    /// Check that a blob exists and still has the content its name claims
+    pub fn verify(&self, digest: &str) -> Result<()> {
+        let Some(blob) = self.find(digest) else {
+            bail!("blob {} is missing", short(digest));
+        };
+        if !copy_checked(&blob, digest, &mut io::sink())? {
+            bail!("blob {} is corrupted", short(digest));
+        }
+        Ok(())
+    }
+
+    /// Every stored blob and its size on disk
+    pub fn sizes(&self) -> Result<HashMap<String, (PathBuf, u64)>> {
+        let mut blobs = HashMap::new();
+        let shards = match fs::read_dir(&self.dir) {
+            Ok(shards) => shards,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(blobs),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.dir.display()))
+            }
+        };
+
+        for shard in shards {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for blob in fs::read_dir(shard.path())? {
+                let blob = blob?;
+                let name = blob.file_name().to_string_lossy().into_owned();
+                let digest = name.trim_end_matches(COMPRESSED_SUFFIX);
+                if !backend::is_valid_key(digest) {
+                    continue;
+                }
+                // Another rush process may have removed it meanwhile
+                match blob.metadata() {
+                    Ok(metadata) => {
+                        blobs.insert(digest.to_string(), (blob.path(), metadata.len()));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+
+        Ok(blobs)
+    }
+
+    /// Delete blobs no entry refers to, returning the bytes freed
+    pub fn collect_garbage(&self, referenced: &HashMap<String, usize>) -> Result<u64> {
+        let mut freed = 0;
+        for (digest, (path, size)) in self.sizes()? {
+            if !referenced.contains_key(&digest) && fs::remove_file(&path).is_ok() {
+                freed += size;
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Remove one blob, returning the bytes freed
+    pub fn remove(&self, digest: &str) -> u64 {
+        let Some(blob) = self.find(digest) else {
+            return 0;
+        };
+        let size = fs::metadata(&blob).map(|m| m.len()).unwrap_or(0);
+        match fs::remove_file(&blob) {
+            Ok(()) => size,
+            Err(_) => 0,
+        }
+    }
+
+    fn find(&self, digest: &str) -> Option<PathBuf> {
+        [true, false]
+            .into_iter()
+            .map(|compressed| self.blob_path(digest, compressed))
+            .find(|path| path.exists())
+    }
+
+    fn blob_path(&self, digest: &str, compressed: bool) -> PathBuf {
+        let name = if compressed {
+            format!("{digest}{COMPRESSED_SUFFIX}")
+        } else {
+            digest.to_string()
+        };
+        self.dir.join(&digest[..2]).join(name)
+    }
+}
+
+/// Copy a blob's content to `out`, decompressing it if needed. Returns false
+/// if the blob can't be read back or doesn't match its digest; only failing
+/// to write to `out` is an error.
+fn copy_checked(blob: &Path, digest: &str, out: &mut dyn Write) -> Result<bool> {
+    let file = File::open(blob)?;
+    let mut reader: Box<dyn Read> = if is_compressed(blob) {
+        match zstd::Decoder::new(file) {
+            Ok(decoder) => Box::new(decoder),
+            Err(_) => return Ok(false),
+        }
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return Ok(false),
+        };
+        hasher.update(&buffer[..read]);
+        out.write_all(&buffer[..read])?;
+    }
+
+    Ok(format!("{:x}", hasher.finalize()) == digest)
+}
+
+/// Unix permission bits of a file, or a plain file's on other platforms
+pub fn file_mode(path: &Path) -> Result<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(fs::metadata(path)?.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(0o644)
+    }
+}
+
+fn is_hard_linked(path: &Path) -> Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(fs::metadata(path)?.nlink() > 1)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(false)
+    }
+}
+
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+    }
+    Ok(())
+}
+
+fn is_compressed(blob: &Path) -> bool {
+    blob.to_string_lossy().ends_with(COMPRESSED_SUFFIX)
+}
+
+fn short(digest: &str) -> &str {
+    &digest[..digest.len().min(12)]
+}
@@ -1,18 +1,211 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
-use std::path::Path;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug)]
+use crate::backend::{self, CacheBackend, EntryKind};
+use crate::blobstore::{self, BlobStore, DEFAULT_COMPRESSION};
+use crate::config::{CacheConfig, Freshness};
+use crate::fileindex::{self, FileIndex};
+use crate::fsutil::{self, normalize};
+use crate::graph::{Step, StepKind, Task};
+use crate::lock::{FileLock, RunLock};
+use crate::manifest::{self, Manifest, OutputFile};
+use crate::secrets;
+
+const STATS_LOG: &str = "stats.log";
+const DURATIONS_LOG: &str = "durations.log";
+/// Runs of a task averaged to estimate how long the next one takes
+const DURATION_HISTORY: usize = 5;
+
+/// Cache key for a task, along with the digest of each input it covers
+#[derive(Debug, Clone)]
+pub struct InputHash {
+    pub hash: String,
+    /// Each input file and its content digest, `None` if it doesn't exist
+    pub inputs: Vec<(PathBuf, Option<String>)>,
+    /// Each variable the key covers and the digest of its value
+    pub env: Vec<(String, String)>,
+}
+
+impl InputHash {
+    fn manifest(&self, task_name: &str, artifact: Option<String>) -> Manifest {
+        Manifest {
+            task: task_name.to_string(),
+            created: Some(SystemTime::now()),
+            inputs: self.inputs.clone(),
+            env: self.env.clone(),
+            artifact,
+            ..Manifest::default()
+        }
+    }
+}
+
+/// What a successful run printed, kept with its cache entry so hits can
+/// replay it
+#[derive(Debug, Clone, Default)]
+pub struct RunLog {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// When the run finished and how long it took
+    pub created: Option<SystemTime>,
+    pub duration: Option<Duration>,
+}
+
+/// A recorded successful run in `.rush-cache`
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub task: String,
+    pub hash: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// When the entry was last written or hit
+    pub last_used: SystemTime,
+}
+
+impl CacheEntry {
+    /// Read and check the entry's manifest
+    pub fn manifest(&self) -> Result<Manifest> {
+        let content = fs::read(&self.path)
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        Manifest::parse(&content)
+    }
+
+    /// Digests of the blobs the entry refers to, none if its manifest is damaged
+    fn blobs(&self) -> Vec<String> {
+        self.manifest()
+            .map(|manifest| manifest.blobs().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TaskStats {
+    pub entries: usize,
+    pub size: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Clone)]
 pub struct TaskCache {
     cache_dir: String,
+    /// Shared caches consulted after `.rush-cache`, in order
+    backends: Arc<Vec<Box<dyn CacheBackend>>>,
+    /// Output files of local entries, shared between entries with equal content
+    blobs: BlobStore,
 }
 
 impl TaskCache {
-    pub fn new() -> Self {
+    pub fn new(settings: &CacheConfig, backends: Vec<Box<dyn CacheBackend>>) -> Self {
+        let cache_dir = ".rush-cache".to_string();
+        let blobs = BlobStore::new(
+            Path::new(&cache_dir).join("blobs"),
+            settings.compression.unwrap_or(DEFAULT_COMPRESSION),
+            settings.restore,
+        );
         Self {
-            cache_dir: ".rush-cache".to_string(),
+            cache_dir,
+            backends: Arc::new(backends),
+            blobs,
+        }
+    }
+
+    /// Lock a task's cache entries against other rush processes, returning
+    /// `None` if another one holds the lock
+    pub fn try_lock_task(&self, task_name: &str) -> Result<Option<FileLock>> {
+        FileLock::try_acquire(&self.task_lock_path(task_name))
+    }
+
+    /// Lock a task's cache entries, waiting for other rush processes
+    pub fn lock_task(&self, task_name: &str) -> Result<FileLock> {
+        FileLock::acquire(&self.task_lock_path(task_name))
+    }
+
+    fn task_lock_path(&self, task_name: &str) -> PathBuf {
+        Path::new(&self.cache_dir)
+            .join("locks")
+            .join(format!("{task_name}.lock"))
+    }
+
+    /// Key of one run of an exclusive task, which is its cache key
+    pub fn run_hash(&self, task: &Task) -> Result<String> {
+        Ok(self.compute_task_hash(task)?.hash)
+    }
+
+    /// Note that an exclusive task just finished successfully, for rush
+    /// processes waiting on its lock. Best effort: they run it themselves
+    /// without this.
+    pub fn record_run(&self, task_name: &str, run_hash: &str) {
+        let line = format!(
+            "{}\t{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            run_hash
+        );
+        let _ = backend::write_atomic(&self.task_run_path(task_name), line.as_bytes());
+    }
+
+    /// Whether the exclusive task finished successfully with `run_hash` after
+    /// `since`, which means a waiting rush can use that run instead of its own
+    pub fn ran_since(&self, task_name: &str, run_hash: &str, since: SystemTime) -> bool {
+        let Ok(record) = fs::read_to_string(self.task_run_path(task_name)) else {
+            return false;
+        };
+        let Some((finished, hash)) = record.trim().split_once('\t') else {
+            return false;
+        };
+        let since = since
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        hash == run_hash
+            && finished
+                .parse::<u128>()
+                .is_ok_and(|finished| finished >= since)
+    }
+
+    fn task_run_path(&self, task_name: &str) -> PathBuf {
+        Path::new(&self.cache_dir)
+            .join("locks")
+            .join(format!("{task_name}.done"))
+    }
+
+    /// Lock the whole checkout for one rush invocation
+    pub fn lock_run(&self, task_name: &str) -> Result<RunLock> {
+        RunLock::acquire(&Path::new(&self.cache_dir).join("run.lock"), task_name)
+    }
+
+    /// Lock the blob store: shared while storing outputs until the entry
+    /// referring to them is written, exclusive while deleting unreferenced
+    /// blobs
+    fn lock_blobs(&self, exclusive: bool) -> Result<FileLock> {
+        let path = Path::new(&self.cache_dir).join("blobs.lock");
+        if exclusive {
+            FileLock::acquire(&path)
+        } else {
+            FileLock::acquire_shared(&path)
+        }
+    }
+
+    /// Replace outputs that are still hard links into the blob store with
+    /// copies, before a task that may write into them in place runs
+    pub fn detach_outputs(&self, outputs: &[String]) -> Result<()> {
+        if outputs.is_empty() {
+            return Ok(());
         }
+        for file in expand_patterns(outputs)? {
+            if file.is_file() {
+                self.blobs.detach(&file)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn ensure_cache_dir(&self) -> Result<()> {
@@ -20,50 +213,936 @@ impl TaskCache {
         Ok(())
     }
 
-    pub fn compute_task_hash(&self, task_name: &str, cache_files: &[String]) -> Result<String> {
+    pub fn compute_task_hash(&self, task: &Task) -> Result<InputHash> {
+        let files = expand_patterns(&task.cache_files)?;
+        let existing: Vec<PathBuf> = files.iter().filter(|f| f.exists()).cloned().collect();
+
+        // Unchanged files are looked up in the index; the rest are read in parallel
+        let digests = if task.freshness == Freshness::Hybrid {
+            let mut index = FileIndex::load(Path::new(&self.cache_dir).join("file-index"));
+            let digests = index.digests(&existing)?;
+            index.save()?;
+            digests
+        } else {
+            fileindex::hash_files(&existing)?
+        };
+        let mut digests = existing.into_iter().zip(digests).peekable();
+
         let mut hasher = Sha256::new();
+        let mut inputs = Vec::with_capacity(files.len());
 
         // Hash the task name
-        hasher.update(task_name.as_bytes());
+        hasher.update(task.name.as_bytes());
 
-        // Hash each cache file's content
-        for file_path in cache_files {
-            if Path::new(file_path).exists() {
-                let content = fs::read(file_path)?;
-                hasher.update(&content);
-            } else {
+        // Hash each cache file's path and content digest
+        for file_path in files {
+            hasher.update(file_path.to_string_lossy().as_bytes());
+            let digest = digests
+                .next_if(|(existing, _)| *existing == file_path)
+                .map(|(_, digest)| digest);
+            match &digest {
+                Some(digest) => hasher.update(digest.as_bytes()),
                 // Hash the fact that the file doesn't exist
-                hasher.update(b"<file-not-found>");
+                None => hasher.update(b"<file-not-found>"),
             }
+            inputs.push((file_path, digest));
         }
 
+        // Hash the environment, so results only carry over between machines
+        // and shells that set the same variables
+        let env = task.cache_env();
+        let mut env_digests = Vec::with_capacity(env.len());
+        for (key, value) in &env {
+            hasher.update(b"\0env\0");
+            hasher.update(key.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(value.as_bytes());
+            // Markers are shared, and a short secret could be guessed from its digest
+            let digest = if task.is_secret(key) {
+                secrets::MASK.to_string()
+            } else {
+                manifest::sha256_hex(value.as_bytes())
+            };
+            env_digests.push((key.clone(), digest));
+        }
+
+        // Hash the commands, so a result only counts for the exact commands
+        // that produced it, whatever parameters they were rendered with
+        hash_steps(&mut hasher, &task.steps);
+
         let result = hasher.finalize();
-        Ok(format!("{result:x}"))
+        Ok(InputHash {
+            hash: format!("{result:x}"),
+            inputs,
+            env: env_digests,
+        })
+    }
+
+    /// Make-style freshness: every output exists and none is older than the
+    /// newest input
+    pub fn outputs_up_to_date(&self, inputs: &[String], outputs: &[String]) -> Result<bool> {
+        let mut newest_input = None;
+        for input in expand_patterns(inputs)? {
+            match fs::metadata(&input) {
+                Ok(metadata) => newest_input = newest_input.max(Some(metadata.modified()?)),
+                // A missing input can't be checked, so the outputs can't be trusted
+                Err(_) => return Ok(false),
+            }
+        }
+
+        let mut oldest_output = None;
+        for output in expand_patterns(outputs)? {
+            match fs::metadata(&output) {
+                Ok(metadata) => {
+                    let modified = metadata.modified()?;
+                    oldest_output =
+                        Some(oldest_output.map_or(modified, |o: SystemTime| o.min(modified)));
+                }
+                Err(_) => return Ok(false),
+            }
+        }
+
+        Ok(match (newest_input, oldest_output) {
+            (Some(input), Some(output)) => output >= input,
+            (None, Some(_)) => true,
+            (_, None) => false,
+        })
     }
 
-    pub fn is_cached(&self, task_name: &str, hash: &str) -> bool {
-        let cache_file = format!("{}/{}.{}", self.cache_dir, task_name, hash);
-        Path::new(&cache_file).exists()
+    fn marker_path(&self, task_name: &str, hash: &str) -> PathBuf {
+        Path::new(&self.cache_dir).join(format!("{task_name}.{hash}"))
     }
 
-    pub fn mark_cached(&self, task_name: &str, hash: &str) -> Result<()> {
+    /// The entry's manifest if the task already succeeded with these inputs,
+    /// putting back any of its stored outputs that are missing or changed. A
+    /// hit refreshes the entry's modification time, which `prune` uses as its
+    /// last use.
+    ///
+    /// A damaged marker is reported, removed and treated as a miss, as is an
+    /// entry whose outputs can't be restored.
+    pub fn is_cached(&self, task_name: &str, hash: &str) -> Option<Manifest> {
+        let marker = self.marker_path(task_name, hash);
+        let content = fs::read(&marker).ok()?;
+
+        let checked = Manifest::parse(&content).and_then(|manifest| {
+            if manifest.task != task_name {
+                bail!("entry belongs to task '{}'", manifest.task);
+            }
+            Ok(manifest)
+        });
+        let manifest = match checked {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                eprintln!("⚠️  Ignoring corrupted cache entry for '{task_name}': {e}");
+                let _ = fs::remove_file(&marker);
+                return None;
+            }
+        };
+
+        if let Err(e) = self.restore_outputs(&manifest.outputs) {
+            eprintln!("⚠️  Not using cache entry for '{task_name}': {e:#}");
+            return None;
+        }
+
+        if let Ok(file) = fs::File::options().append(true).open(&marker) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(manifest)
+    }
+
+    /// The output of the run that created an entry
+    pub fn run_log(&self, manifest: &Manifest) -> Result<RunLog> {
+        let read = |digest: &Option<String>| match digest {
+            Some(digest) => self.blobs.get(digest),
+            None => Ok(Vec::new()),
+        };
+        Ok(RunLog {
+            stdout: read(&manifest.stdout)?,
+            stderr: read(&manifest.stderr)?,
+            created: manifest.created,
+            duration: manifest.duration,
+        })
+    }
+
+    /// Store a run's output as blobs in the manifest. Failures are reported
+    /// and leave the entry without a log.
+    fn store_log(&self, task_name: &str, log: &RunLog, manifest: &mut Manifest) {
+        let store = |data: &[u8]| -> Result<Option<String>> {
+            if data.is_empty() {
+                return Ok(None);
+            }
+            self.blobs.put_bytes(data).map(Some)
+        };
+        let stored = store(&log.stdout).and_then(|stdout| Ok((stdout, store(&log.stderr)?)));
+        match stored {
+            Ok((stdout, stderr)) => {
+                manifest.stdout = stdout;
+                manifest.stderr = stderr;
+                manifest.duration = log.duration;
+            }
+            Err(e) => eprintln!("⚠️  Not storing output of '{task_name}' in the cache: {e:#}"),
+        }
+    }
+
+    /// Restore outputs whose content differs from the stored blob. Unchanged
+    /// files are recognised through the file index without reading them.
+    fn restore_outputs(&self, outputs: &[OutputFile]) -> Result<()> {
+        if outputs.is_empty() {
+            return Ok(());
+        }
+
+        let existing: Vec<PathBuf> = outputs
+            .iter()
+            .map(|output| output.path.clone())
+            .filter(|path| path.is_file())
+            .collect();
+        let mut index = FileIndex::load(Path::new(&self.cache_dir).join("file-index"));
+        let mut current = existing.iter().zip(index.digests(&existing)?).peekable();
+
+        for output in outputs {
+            let digest = current
+                .next_if(|(path, _)| **path == output.path)
+                .map(|(_, digest)| digest);
+            if digest.as_deref() != Some(output.digest.as_str()) {
+                self.blobs
+                    .restore(&output.digest, &output.path, output.mode)
+                    .with_context(|| format!("Failed to restore {}", output.path.display()))?;
+            }
+        }
+        index.save()
+    }
+
+    /// Store the task's output files in the blob store, skipping outputs that
+    /// weren't created. Failures are reported and leave the entry without
+    /// outputs, so a hit simply won't restore them.
+    fn store_outputs(&self, task_name: &str, outputs: &[String]) -> Vec<OutputFile> {
+        let stored = expand_patterns(outputs).and_then(|files| {
+            files
+                .into_iter()
+                .filter(|file| file.is_file())
+                .map(|file| {
+                    check_output_path(&file)?;
+                    Ok(OutputFile {
+                        digest: self.blobs.put(&file)?,
+                        mode: blobstore::file_mode(&file)?,
+                        path: file,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        });
+        stored.unwrap_or_else(|e| {
+            eprintln!("⚠️  Not storing outputs of '{task_name}' in the cache: {e:#}");
+            Vec::new()
+        })
+    }
+
+    /// Record a successful run, its outputs and, if given, what it printed,
+    /// replacing older entries for the task. The marker holds the manifest
+    /// shown by `rush cache inspect`.
+    pub fn mark_cached(
+        &self,
+        task_name: &str,
+        inputs: &InputHash,
+        outputs: &[String],
+        log: Option<&RunLog>,
+    ) -> Result<()> {
         self.ensure_cache_dir()?;
 
         // Remove old cache files for this task
-        if let Ok(entries) = fs::read_dir(&self.cache_dir) {
-            for entry in entries.flatten() {
-                let file_name = entry.file_name();
-                let file_name_str = file_name.to_string_lossy();
-                if file_name_str.starts_with(&format!("{task_name}.")) {
-                    let _ = fs::remove_file(entry.path());
-                }
+        let mut replaced_blobs = Vec::new();
+        for entry in self.entries()? {
+            if entry.task == task_name && entry.hash != inputs.hash {
+                replaced_blobs.extend(entry.blobs());
+                let _ = fs::remove_file(&entry.path);
             }
         }
 
-        // Create new cache marker
-        let cache_file = format!("{}/{}.{}", self.cache_dir, task_name, hash);
-        fs::write(cache_file, "")?;
+        // Create new cache marker. Blobs found already stored aren't
+        // referenced until the marker exists, so garbage collection waits
+        // for it.
+        {
+            let _storing = self.lock_blobs(false)?;
+            let mut manifest = inputs.manifest(task_name, None);
+            manifest.outputs = self.store_outputs(task_name, outputs);
+            if let Some(log) = log {
+                self.store_log(task_name, log, &mut manifest);
+            }
+            backend::write_atomic(
+                &self.marker_path(task_name, &inputs.hash),
+                &manifest.to_bytes(),
+            )?;
+        }
 
+        // Drop the replaced entries' blobs unless another entry still uses them
+        if !replaced_blobs.is_empty() {
+            let _collecting = self.lock_blobs(true)?;
+            let references = self.blob_references(&self.entries()?);
+            for digest in replaced_blobs {
+                if !references.contains_key(&digest) {
+                    self.blobs.remove(&digest);
+                }
+            }
+        }
         Ok(())
     }
+
+    /// How many entries refer to each stored blob. Entries whose manifest
+    /// can't be read refer to none.
+    fn blob_references(&self, entries: &[CacheEntry]) -> HashMap<String, usize> {
+        let mut references = HashMap::new();
+        for entry in entries {
+            for digest in entry.blobs() {
+                *references.entry(digest).or_default() += 1;
+            }
+        }
+        references
+    }
+
+    /// Delete blobs no entry refers to any more, returning the bytes freed
+    fn collect_garbage(&self) -> Result<u64> {
+        let _collecting = self.lock_blobs(true)?;
+        let references = self.blob_references(&self.entries()?);
+        self.blobs.collect_garbage(&references)
+    }
+
+    /// Check every entry's manifest, returning the damaged ones and what is
+    /// wrong with them. With `remove`, damaged entries are deleted.
+    pub fn verify(&self, remove: bool) -> Result<Vec<(CacheEntry, String)>> {
+        let mut damaged = Vec::new();
+        for entry in self.entries()? {
+            let problem = match entry.manifest() {
+                Ok(manifest) if manifest.task != entry.task => {
+                    format!("entry belongs to task '{}'", manifest.task)
+                }
+                Ok(manifest) => {
+                    let broken = manifest
+                        .blobs()
+                        .find_map(|digest| self.blobs.verify(digest).err());
+                    match broken {
+                        Some(e) => format!("{e:#}"),
+                        None => continue,
+                    }
+                }
+                Err(e) => format!("{e:#}"),
+            };
+            if remove {
+                fs::remove_file(&entry.path)
+                    .with_context(|| format!("Failed to remove {}", entry.path.display()))?;
+                // A corrupted blob is useless to every entry sharing it
+                for digest in entry.blobs() {
+                    if self.blobs.verify(&digest).is_err() {
+                        self.blobs.remove(&digest);
+                    }
+                }
+            }
+            damaged.push((entry, problem));
+        }
+        if remove {
+            self.collect_garbage()?;
+        }
+        Ok(damaged)
+    }
+
+    /// Append a hit or miss for `rush cache stats`. Best effort: statistics
+    /// never fail a run.
+    pub fn record(&self, task_name: &str, hit: bool) {
+        if self.ensure_cache_dir().is_err() {
+            return;
+        }
+        let line = format!(
+            "{}\t{}\t{}\n",
+            unix_secs(SystemTime::now()),
+            if hit { "hit" } else { "miss" },
+            task_name
+        );
+        // Appends this small are atomic, so concurrent runs don't interleave lines
+        if let Ok(mut log) = fs::File::options()
+            .create(true)
+            .append(true)
+            .open(Path::new(&self.cache_dir).join(STATS_LOG))
+        {
+            let _ = log.write_all(line.as_bytes());
+        }
+    }
+
+    /// Append how long a task's commands took, for the parallel scheduler's
+    /// estimates. Once a task has twice the runs the estimates use, the log
+    /// is rewritten with only the recent ones. Best effort, like `record`.
+    pub fn record_duration(&self, task_name: &str, elapsed: Duration) {
+        if self.ensure_cache_dir().is_err() {
+            return;
+        }
+        let Ok(_lock) = FileLock::acquire(&Path::new(&self.cache_dir).join("durations.lock"))
+        else {
+            return;
+        };
+        let path = Path::new(&self.cache_dir).join(DURATIONS_LOG);
+        let line = format!(
+            "{}\t{}\t{}\n",
+            unix_secs(SystemTime::now()),
+            elapsed.as_millis(),
+            task_name
+        );
+
+        let log = fs::read_to_string(&path).unwrap_or_default();
+        let runs = duration_lines(&log)
+            .filter(|(_, task)| *task == task_name)
+            .count();
+        if runs < 2 * DURATION_HISTORY {
+            if let Ok(mut log) = fs::File::options().create(true).append(true).open(&path) {
+                let _ = log.write_all(line.as_bytes());
+            }
+            return;
+        }
+
+        // Keep each task's last runs, in their original order
+        let lines: Vec<&str> = log
+            .lines()
+            .chain(std::iter::once(line.trim_end()))
+            .collect();
+        let mut kept: HashMap<&str, usize> = HashMap::new();
+        let mut trimmed: Vec<&str> = Vec::with_capacity(lines.len());
+        for line in lines.into_iter().rev() {
+            let Some((_, task)) = duration_lines(line).next() else {
+                continue;
+            };
+            let count = kept.entry(task).or_default();
+            if *count < DURATION_HISTORY {
+                *count += 1;
+                trimmed.push(line);
+            }
+        }
+        trimmed.reverse();
+        let mut content = trimmed.join("\n");
+        content.push('\n');
+        let _ = backend::write_atomic(&path, content.as_bytes());
+    }
+
+    /// Expected duration of each task that ran before: the mean of its last
+    /// few runs
+    pub fn durations(&self) -> HashMap<String, Duration> {
+        let log =
+            fs::read_to_string(Path::new(&self.cache_dir).join(DURATIONS_LOG)).unwrap_or_default();
+        let mut runs: HashMap<String, Vec<u64>> = HashMap::new();
+        for (millis, task) in duration_lines(&log) {
+            runs.entry(task.to_string()).or_default().push(millis);
+        }
+
+        runs.into_iter()
+            .map(|(task, millis)| {
+                let recent = &millis[millis.len().saturating_sub(DURATION_HISTORY)..];
+                let mean = recent.iter().sum::<u64>() / recent.len() as u64;
+                (task, Duration::from_millis(mean))
+            })
+            .collect()
+    }
+
+    /// Every entry in `.rush-cache`, oldest use first
+    pub fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let mut entries = Vec::new();
+        let dir = match fs::read_dir(&self.cache_dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.cache_dir)),
+        };
+
+        for file in dir {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            // Markers are `<task>.<hash>`; task names may contain dots themselves
+            let Some((task, hash)) = name.rsplit_once('.') else {
+                continue;
+            };
+            if !backend::is_valid_key(hash) {
+                continue;
+            }
+            // Another rush process may evict or replace the entry meanwhile
+            let metadata = match file.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            entries.push(CacheEntry {
+                task: task.to_string(),
+                hash: hash.to_string(),
+                path: file.path(),
+                size: metadata.len(),
+                last_used: metadata.modified()?,
+            });
+        }
+
+        entries.sort_by_key(|entry| entry.last_used);
+        Ok(entries)
+    }
+
+    /// Entries, size and hit ratio for each task
+    pub fn stats(&self) -> Result<BTreeMap<String, TaskStats>> {
+        let mut stats: BTreeMap<String, TaskStats> = BTreeMap::new();
+
+        // Blobs shared by several tasks count towards each of them
+        let blob_sizes = self.blobs.sizes()?;
+        for entry in self.entries()? {
+            let blobs = entry.blobs();
+            let task = stats.entry(entry.task).or_default();
+            task.entries += 1;
+            task.size += entry.size;
+            for digest in blobs {
+                task.size += blob_sizes.get(&digest).map_or(0, |(_, size)| *size);
+            }
+        }
+
+        let log =
+            fs::read_to_string(Path::new(&self.cache_dir).join(STATS_LOG)).unwrap_or_default();
+        for line in log.lines() {
+            let mut fields = line.splitn(3, '\t').skip(1);
+            let (Some(outcome), Some(task)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let task = stats.entry(task.to_string()).or_default();
+            match outcome {
+                "hit" => task.hits += 1,
+                "miss" => task.misses += 1,
+                _ => {}
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Total size of everything in `.rush-cache`, including stored outputs,
+    /// the file index and statistics
+    pub fn disk_usage(&self) -> Result<u64> {
+        let mut total = 0;
+        for file in expand_patterns(std::slice::from_ref(&self.cache_dir))? {
+            total += fs::metadata(&file).map(|m| m.len()).unwrap_or(0);
+        }
+        Ok(total)
+    }
+
+    /// Remove a task's entries, or every entry and stored output when
+    /// `task_name` is `None`.
+    /// Returns the number of entries removed.
+    pub fn clean(&self, task_name: Option<&str>) -> Result<usize> {
+        let Some(task_name) = task_name else {
+            // Only results go: locks held by running rush processes, the file
+            // index and a `cache serve` store below the cache dir stay
+            let _collecting = self.lock_blobs(true)?;
+            let entries = self.entries()?;
+            for entry in &entries {
+                match fs::remove_file(&entry.path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("Failed to remove {}", entry.path.display()))
+                    }
+                }
+            }
+            let blobs = Path::new(&self.cache_dir).join("blobs");
+            match fs::remove_dir_all(&blobs) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to remove {}", blobs.display()))
+                }
+            }
+            return Ok(entries.len());
+        };
+
+        let entries = self.entries()?;
+        let mut removed = 0;
+        for entry in entries.iter().filter(|entry| entry.task == task_name) {
+            fs::remove_file(&entry.path)
+                .with_context(|| format!("Failed to remove {}", entry.path.display()))?;
+            removed += 1;
+        }
+        self.collect_garbage()?;
+        Ok(removed)
+    }
+
+    /// Evict entries unused for longer than `older_than`, then least recently
+    /// used entries until the rest, with the blobs they refer to, fit in
+    /// `max_size` bytes. Blobs left unreferenced are deleted too. Returns the
+    /// removed entries and the bytes freed.
+    pub fn prune(
+        &self,
+        max_size: Option<u64>,
+        older_than: Option<Duration>,
+    ) -> Result<(Vec<CacheEntry>, u64)> {
+        let _collecting = self.lock_blobs(true)?;
+        let entries = self.entries()?;
+        let now = SystemTime::now();
+        let blob_sizes = self.blobs.sizes()?;
+        let mut references = self.blob_references(&entries);
+
+        // Orphaned blobs go first, they don't belong to any entry
+        let mut freed = self.blobs.collect_garbage(&references)?;
+        let mut remaining: u64 = entries.iter().map(|entry| entry.size).sum::<u64>()
+            + references
+                .keys()
+                .filter_map(|digest| blob_sizes.get(digest))
+                .map(|(_, size)| size)
+                .sum::<u64>();
+        let mut removed = Vec::new();
+
+        for entry in entries {
+            let expired = older_than.is_some_and(|limit| {
+                now.duration_since(entry.last_used)
+                    .is_ok_and(|age| age > limit)
+            });
+            let over_size = max_size.is_some_and(|limit| remaining > limit);
+            if !expired && !over_size {
+                continue;
+            }
+
+            let blobs = entry.blobs();
+            fs::remove_file(&entry.path)
+                .with_context(|| format!("Failed to remove {}", entry.path.display()))?;
+            remaining -= entry.size;
+            freed += entry.size;
+
+            for digest in blobs {
+                let Some(count) = references.get_mut(&digest) else {
+                    continue;
+                };
+                *count -= 1;
+                if *count == 0 {
+                    references.remove(&digest);
+                    let size = self.blobs.remove(&digest);
+                    remaining = remaining.saturating_sub(size);
+                    freed += size;
+                }
+            }
+            removed.push(entry);
+        }
+
+        Ok((removed, freed))
+    }
+
+    /// Look for a result in the shared backends after a local miss. On a hit
+    /// the task's outputs are unpacked into place and the result is recorded
+    /// locally. Returns the backend the result came from.
+    ///
+    /// Backend failures are reported and treated as a miss, since the task
+    /// can always just run.
+    pub fn fetch_remote(&self, task: &Task, inputs: &InputHash) -> Option<String> {
+        let task_name = &task.name;
+        for backend in self.backends.iter() {
+            match self.fetch_from(backend.as_ref(), task, inputs) {
+                Ok(true) => return Some(backend.describe()),
+                Ok(false) => {}
+                Err(e) => eprintln!(
+                    "⚠️  Not using cache entry for '{}' from {}: {:#}",
+                    task_name,
+                    backend.describe(),
+                    e
+                ),
+            }
+        }
+        None
+    }
+
+    fn fetch_from(
+        &self,
+        backend: &dyn CacheBackend,
+        task: &Task,
+        inputs: &InputHash,
+    ) -> Result<bool> {
+        let (task_name, outputs) = (&task.name, &task.outputs);
+        let hash = &inputs.hash;
+        let Some(mut marker) = backend.get(EntryKind::Marker, hash)? else {
+            return Ok(false);
+        };
+        let mut bytes = Vec::new();
+        marker
+            .read_to_end(&mut bytes)
+            .context("Cache download failed")?;
+        let manifest = Manifest::parse(&bytes).context("corrupted marker")?;
+
+        if !outputs.is_empty() {
+            // Entries from runs that couldn't archive their outputs can't be restored
+            let Some(expected) = &manifest.artifact else {
+                return Ok(false);
+            };
+            // Artifacts are uploaded before markers, so this only misses if
+            // the entry was removed from the backend in between
+            let Some(mut archive) = backend.get(EntryKind::Artifact, hash)? else {
+                return Ok(false);
+            };
+            // Spooled to disk, so the archive is checked before anything is
+            // unpacked without holding all of it in memory
+            let (spool_path, mut spool) = fsutil::create_private_temp("archive", task_name, "tar")?;
+            let restored = fileindex::copy_hashed(&mut archive, &mut spool)
+                .context("Cache download failed")
+                .and_then(|digest| {
+                    if digest != *expected {
+                        bail!("corrupted output archive, checksum mismatch");
+                    }
+                    spool.rewind()?;
+                    unpack_outputs(io::BufReader::new(&spool), outputs)
+                        .with_context(|| format!("Failed to restore outputs of '{task_name}'"))
+                });
+            let _ = fs::remove_file(&spool_path);
+            restored?;
+        }
+
+        self.mark_cached(task_name, inputs, outputs, None)?;
+        Ok(true)
+    }
+
+    /// Share a successful result with every writable backend
+    pub fn upload(&self, task: &Task, inputs: &InputHash) {
+        let (task_name, outputs) = (&task.name, &task.outputs);
+        let writable: Vec<_> = self
+            .backends
+            .iter()
+            .filter(|backend| !backend.read_only())
+            .collect();
+        if writable.is_empty() {
+            return;
+        }
+
+        let archive = if outputs.is_empty() {
+            None
+        } else {
+            match pack_outputs(task_name, outputs) {
+                Ok(archive) => Some(archive),
+                Err(e) => {
+                    eprintln!("⚠️  Not sharing '{task_name}' with cache backends: {e:#}");
+                    return;
+                }
+            }
+        };
+
+        let hash = &inputs.hash;
+        let marker = inputs
+            .manifest(
+                task_name,
+                archive.as_ref().map(|(_, digest)| digest.clone()),
+            )
+            .to_bytes();
+
+        for backend in writable {
+            let uploaded = archive
+                .as_ref()
+                .map_or(Ok(()), |(path, _)| {
+                    let mut file = fs::File::open(path)?;
+                    let len = file.metadata()?.len();
+                    backend.put(EntryKind::Artifact, hash, &mut file, len)
+                })
+                .and_then(|()| {
+                    backend.put(
+                        EntryKind::Marker,
+                        hash,
+                        &mut marker.as_slice(),
+                        marker.len() as u64,
+                    )
+                });
+            if let Err(e) = uploaded {
+                eprintln!(
+                    "⚠️  Failed to upload '{}' to {}: {:#}",
+                    task_name,
+                    backend.describe(),
+                    e
+                );
+            }
+        }
+        if let Some((path, _)) = &archive {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Hash the commands of `steps`, which parameters and passthrough arguments
+/// are rendered into
+fn hash_steps(hasher: &mut Sha256, steps: &[Step]) {
+    for step in steps {
+        hasher.update(b"\0step\0");
+        match &step.kind {
+            StepKind::Command(cmd) => hasher.update(cmd.as_bytes()),
+            StepKind::Script {
+                interpreter,
+                source,
+            } => {
+                hasher.update(interpreter.as_deref().unwrap_or_default().as_bytes());
+                hasher.update(b"\0");
+                hasher.update(source.as_bytes());
+            }
+            StepKind::Task(name) => hasher.update(name.as_bytes()),
+            StepKind::Inline(task) => hash_steps(hasher, &task.steps),
+        }
+    }
+}
+
+/// Milliseconds and task name of each well-formed line of the durations log
+fn duration_lines(log: &str) -> impl Iterator<Item = (u64, &str)> {
+    log.lines().filter_map(|line| {
+        let mut fields = line.splitn(3, '\t').skip(1);
+        Some((fields.next()?.parse().ok()?, fields.next()?))
+    })
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Tar archive of a task's outputs, with paths relative to the project,
+/// written to a temporary file the caller removes. Returns the file and the
+/// archive's digest.
+fn pack_outputs(task_name: &str, outputs: &[String]) -> Result<(PathBuf, String)> {
+    let (path, file) = fsutil::create_private_temp("archive", task_name, "tar")?;
+    let packed =
+        write_archive(io::BufWriter::new(file), outputs).and_then(|()| fileindex::hash_file(&path));
+    match packed {
+        Ok(digest) => Ok((path, digest)),
+        Err(e) => {
+            let _ = fs::remove_file(&path);
+            Err(e)
+        }
+    }
+}
+
+fn write_archive(out: impl Write, outputs: &[String]) -> Result<()> {
+    let mut builder = tar::Builder::new(out);
+
+    for file in expand_patterns(outputs)? {
+        if !file.exists() {
+            bail!("output {} was not created", file.display());
+        }
+        check_output_path(&file)?;
+        builder
+            .append_path(&file)
+            .with_context(|| format!("Failed to archive {}", file.display()))?;
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Extract an output archive from a shared backend. Anyone able to write to
+/// the backend controls its content, so only regular files matching the
+/// task's `outputs` patterns are written; anything else fails the restore.
+fn unpack_outputs(archive: impl io::Read, outputs: &[String]) -> Result<()> {
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            bail!("archive entry {} is not a regular file", path.display());
+        }
+        check_output_path(&path)?;
+        let path = normalize(&path);
+        if !patterns_cover(outputs, &path)? {
+            bail!(
+                "archive entry {} is not an output of the task",
+                path.display()
+            );
+        }
+
+        let mode = entry.header().mode()?;
+        backend::write_atomic_with(&path, |file| {
+            io::copy(&mut entry, file)
+                .with_context(|| format!("Failed to extract {}", path.display()))?;
+            Ok(())
+        })?;
+        blobstore::set_mode(&path, mode & 0o777)?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is among the files `patterns` expand to, judged from the
+/// patterns alone, so files that don't exist yet can be checked
+fn patterns_cover(patterns: &[String], path: &Path) -> Result<bool> {
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    let mut covered = false;
+
+    for pattern in patterns {
+        if let Some(exclude) = pattern.strip_prefix('!') {
+            let exclude = glob::Pattern::new(&normalize(Path::new(exclude)).to_string_lossy())
+                .with_context(|| format!("Invalid exclude pattern '{pattern}'"))?;
+            if exclude.matches_path_with(path, options) {
+                return Ok(false);
+            }
+            continue;
+        }
+
+        let normalized = normalize(Path::new(pattern));
+        if !pattern.contains(['*', '?', '[']) {
+            // A file or a directory holding it
+            covered |= path.starts_with(&normalized);
+            continue;
+        }
+        let glob = glob::Pattern::new(&normalized.to_string_lossy())
+            .with_context(|| format!("Invalid cache pattern '{pattern}'"))?;
+        covered |= path
+            .ancestors()
+            .any(|ancestor| glob.matches_path_with(ancestor, options));
+    }
+
+    Ok(covered)
+}
+
+/// Outputs are restored relative to the project, so they must stay inside it
+fn check_output_path(file: &Path) -> Result<()> {
+    if !file.is_relative()
+        || file
+            .components()
+            .any(|c| matches!(c, std::path::Component::ParentDir))
+    {
+        bail!("output {} is outside the project", file.display());
+    }
+    Ok(())
+}
+
+/// Expand cache and output patterns into a sorted list of files.
+///
+/// Patterns may be globs (`src/**/*.rs`) or directories, which include every
+/// file below them. Patterns starting with `!` remove earlier matches. Literal
+/// paths that don't exist are kept, so their absence is still noticed.
+pub fn expand_patterns(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = BTreeSet::new();
+    let mut excludes = Vec::new();
+
+    for pattern in patterns {
+        if let Some(exclude) = pattern.strip_prefix('!') {
+            excludes.push(
+                glob::Pattern::new(exclude)
+                    .with_context(|| format!("Invalid exclude pattern '{pattern}'"))?,
+            );
+            continue;
+        }
+
+        if !pattern.contains(['*', '?', '[']) {
+            add_path(PathBuf::from(pattern), &mut files)?;
+            continue;
+        }
+
+        for entry in
+            glob::glob(pattern).with_context(|| format!("Invalid cache pattern '{pattern}'"))?
+        {
+            add_path(entry?, &mut files)?;
+        }
+    }
+
+    Ok(files
+        .into_iter()
+        .filter(|file| !excludes.iter().any(|exclude| exclude.matches_path(file)))
+        .collect())
+}
+
+fn add_path(path: PathBuf, files: &mut BTreeSet<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        files.insert(path);
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(&path)? {
+        add_path(entry?.path(), files)?;
+    }
+    Ok(())
 }
@@ -0,0 +1,370 @@
+use anyhow::{anyhow, bail, Result};
+use clap::{Arg, ArgMatches, Command};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::backend::DirectoryBackend;
+use crate::cache::TaskCache;
+use crate::config::CacheConfig;
+use crate::server;
+
+/// The `rush cache` subcommand
+pub fn command() -> Command {
+    Command::new("cache")
+        .about("Manage the task cache")
+        .subcommand_required(true)
+        .subcommand(Command::new("stats").about("Show entries, size and hit ratio per task"))
+        .subcommand(
+            Command::new("clean")
+                .about("Remove cached results, for one task or all of them")
+                .arg(Arg::new("task").help("Only remove this task's entries")),
+        )
+        .subcommand(
+            Command::new("prune")
+                .about("Evict least recently used entries and unused stored outputs")
+                .arg(
+                    Arg::new("max-size")
+                        .long("max-size")
+                        .value_name("SIZE")
+                        .help("Evict until entries fit in SIZE, e.g. 500MB or 5GB"),
+                )
+                .arg(
+                    Arg::new("older-than")
+                        .long("older-than")
+                        .value_name("AGE")
+                        .help("Evict entries unused for AGE, e.g. 12h or 30d"),
+                ),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("Show the stored hash and input manifest of a task")
+                .arg(Arg::new("task").required(true)),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check cache entries for truncation and tampering")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .value_name("PATH")
+                        .help("Check a directory backend or `cache serve` store instead"),
+                )
+                .arg(
+                    Arg::new("remove")
+                        .long("remove")
+                        .help("Delete damaged entries")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Serve a directory as an HTTP cache backend")
+                .arg(
+                    Arg::new("addr")
+                        .long("addr")
+                        .help("Address to listen on")
+                        .default_value("127.0.0.1:7878"),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .help("Directory to store cache entries in")
+                        .default_value(".rush-cache/server"),
+                )
+                .arg(
+                    Arg::new("read-only")
+                        .long("read-only")
+                        .help("Reject all uploads")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("write-token-env")
+                        .long("write-token-env")
+                        .value_name("VAR")
+                        .help("Environment variable holding the token uploads must send"),
+                )
+                .arg(
+                    Arg::new("max-upload")
+                        .long("max-upload")
+                        .value_name("SIZE")
+                        .help("Reject uploads larger than SIZE")
+                        .default_value("1GB"),
+                ),
+        )
+}
+
+pub async fn run(matches: &ArgMatches) -> Result<()> {
+    let cache = TaskCache::new(&CacheConfig::default(), Vec::new());
+
+    match matches.subcommand() {
+        Some(("stats", _)) => print_stats(&cache),
+        Some(("clean", clean_matches)) => {
+            let task = clean_matches.get_one::<String>("task");
+            let removed = cache.clean(task.map(String::as_str))?;
+            match task {
+                Some(task) => println!("🧹 Removed {removed} cache entries for '{task}'"),
+                None => println!("🧹 Removed {removed} cache entries"),
+            }
+            Ok(())
+        }
+        Some(("prune", prune_matches)) => {
+            let max_size = prune_matches
+                .get_one::<String>("max-size")
+                .map(|size| parse_size(size))
+                .transpose()?;
+            let older_than = prune_matches
+                .get_one::<String>("older-than")
+                .map(|age| parse_age(age))
+                .transpose()?;
+
+            let (removed, freed) = cache.prune(max_size, older_than)?;
+            for entry in &removed {
+                println!("🗑️  {} ({})", entry.task, short_hash(&entry.hash));
+            }
+            println!(
+                "🧹 Pruned {} entries, freeing {}",
+                removed.len(),
+                format_size(freed)
+            );
+            Ok(())
+        }
+        Some(("inspect", inspect_matches)) => {
+            inspect(&cache, inspect_matches.get_one::<String>("task").unwrap())
+        }
+        Some(("verify", verify_matches)) => verify(
+            &cache,
+            verify_matches.get_one::<String>("dir"),
+            verify_matches.get_flag("remove"),
+        ),
+        Some(("serve", serve_matches)) => {
+            let write_token =
+                match serve_matches.get_one::<String>("write-token-env") {
+                    Some(name) => Some(std::env::var(name).map_err(|_| {
+                        anyhow!("--write-token-env names ${name}, which is not set")
+                    })?),
+                    None => None,
+                };
+            let options = server::ServeOptions {
+                addr: serve_matches.get_one::<String>("addr").unwrap().clone(),
+                dir: serve_matches.get_one::<String>("dir").unwrap().into(),
+                read_only: serve_matches.get_flag("read-only"),
+                write_token,
+                max_upload: parse_size(serve_matches.get_one::<String>("max-upload").unwrap())?,
+            };
+            tokio::task::spawn_blocking(move || server::serve(options)).await?
+        }
+        _ => unreachable!("clap requires a cache subcommand"),
+    }
+}
+
+fn print_stats(cache: &TaskCache) -> Result<()> {
+    let stats = cache.stats()?;
+    if stats.is_empty() {
+        println!("📊 Cache is empty");
+        return Ok(());
+    }
+
+    println!(
+        "📊 Cache statistics ({} on disk)\n",
+        format_size(cache.disk_usage()?)
+    );
+    println!(
+        "  {:<24} {:>7} {:>10} {:>6} {:>6} {:>6}",
+        "Task", "Entries", "Size", "Hits", "Misses", "Ratio"
+    );
+    for (task, stats) in &stats {
+        let lookups = stats.hits + stats.misses;
+        let ratio = if lookups == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.0}%", stats.hits as f64 * 100.0 / lookups as f64)
+        };
+        println!(
+            "  {:<24} {:>7} {:>10} {:>6} {:>6} {:>6}",
+            task,
+            stats.entries,
+            format_size(stats.size),
+            stats.hits,
+            stats.misses,
+            ratio
+        );
+    }
+    Ok(())
+}
+
+fn inspect(cache: &TaskCache, task: &str) -> Result<()> {
+    let entries: Vec<_> = cache
+        .entries()?
+        .into_iter()
+        .filter(|entry| entry.task == task)
+        .collect();
+    if entries.is_empty() {
+        bail!("No cache entries for task '{}'", task);
+    }
+
+    for entry in entries {
+        let manifest = entry.manifest()?;
+        println!("📦 {task}");
+        println!("   Hash: {}", entry.hash);
+        if let Some(created) = manifest.created {
+            println!("   Created: {}", format_age(created));
+        }
+        println!("   Last used: {}", format_age(entry.last_used));
+        if let Some(duration) = manifest.duration {
+            println!("   Took: {:.1}s", duration.as_secs_f32());
+        }
+        println!("   Inputs ({}):", manifest.inputs.len());
+        for (path, digest) in &manifest.inputs {
+            match digest {
+                Some(digest) => println!("     {}  {}", short_hash(digest), path.display()),
+                None => println!("     {:<12}  {}", "(missing)", path.display()),
+            }
+        }
+        if !manifest.env.is_empty() {
+            println!("   Environment ({}):", manifest.env.len());
+            for (key, digest) in &manifest.env {
+                println!("     {}  {}", short_hash(digest), key);
+            }
+        }
+        if !manifest.outputs.is_empty() {
+            println!("   Outputs ({}):", manifest.outputs.len());
+            for output in &manifest.outputs {
+                println!(
+                    "     {}  {:o}  {}",
+                    short_hash(&output.digest),
+                    output.mode,
+                    output.path.display()
+                );
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn verify(cache: &TaskCache, dir: Option<&String>, remove: bool) -> Result<()> {
+    let damaged: Vec<(PathBuf, String)> = match dir {
+        Some(dir) => DirectoryBackend::new(dir, false).verify(remove)?,
+        None => cache
+            .verify(remove)?
+            .into_iter()
+            .map(|(entry, problem)| (entry.path, problem))
+            .collect(),
+    };
+
+    for (path, problem) in &damaged {
+        println!("❌ {}: {}", path.display(), problem);
+    }
+
+    match (damaged.len(), remove) {
+        (0, _) => println!("✅ All cache entries are intact"),
+        (count, true) => println!("🧹 Removed {count} damaged cache entries"),
+        (count, false) => bail!(
+            "{} damaged cache entries, run with --remove to delete them",
+            count
+        ),
+    }
+    Ok(())
+}
+
+fn short_hash(hash: &str) -> &str {
+    &hash[..hash.len().min(12)]
+}
+
+pub fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        0..=59 => format!("{secs}s ago"),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("TB", 1 << 40),
+    ("GB", 1 << 30),
+    ("MB", 1 << 20),
+    ("KB", 1 << 10),
+    ("B", 1),
+];
+
+pub fn format_size(bytes: u64) -> String {
+    for (unit, scale) in SIZE_UNITS {
+        if bytes >= *scale && *scale > 1 {
+            return format!("{:.1} {unit}", bytes as f64 / *scale as f64);
+        }
+    }
+    format!("{bytes} B")
+}
+
+/// Parse sizes like `500MB`, `5GB` or `1.5G`, in powers of 1024
+pub fn parse_size(size: &str) -> Result<u64> {
+    let upper = size.trim().to_ascii_uppercase();
+    let split = upper
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(upper.len());
+    let (number, unit) = upper.split_at(split);
+    let unit = unit.trim();
+
+    let scale = SIZE_UNITS
+        .iter()
+        .find(|(name, _)| *name == unit || (unit.len() == 1 && name.starts_with(unit)))
+        .map(|(_, scale)| *scale)
+        .or(unit.is_empty().then_some(1))
+        .ok_or_else(|| anyhow!("Unknown size unit in '{}'", size))?;
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size '{}'", size))?;
+
+    Ok((number * scale as f64) as u64)
+}
+
+/// Parse ages like `90s`, `45m`, `12h`, `30d` or `2w`
+fn parse_age(age: &str) -> Result<Duration> {
+    let age = age.trim();
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (number, unit) = age.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid age '{}'", age))?;
+
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => bail!("Unknown age unit in '{}', use s, m, h, d or w", age),
+    };
+    Ok(Duration::from_secs(number * secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("2KB").unwrap(), 2048);
+        assert_eq!(parse_size("500mb").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(parse_size(" 5 GB ").unwrap(), 5 * 1024 * 1024 * 1024);
+        assert!(parse_size("5XB").is_err());
+        assert!(parse_size("GB").is_err());
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_age("45m").unwrap(), Duration::from_secs(45 * 60));
+        assert_eq!(parse_age("2w").unwrap(), Duration::from_secs(14 * 86400));
+        assert!(parse_age("30").is_err());
+        assert!(parse_age("1y").is_err());
+        assert!(parse_age("d").is_err());
+    }
+}
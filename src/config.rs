@@ -1,56 +1,646 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use crate::backend::BackendConfig;
+use crate::blobstore::{RestoreMode, DEFAULT_COMPRESSION};
+use crate::cachecmd::parse_size;
+use crate::sandbox::Sandbox;
+use crate::shell::Shell;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RushConfig {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub vars: HashMap<String, Scalar>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub env_file: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Variable names (or `*` patterns) whose values are masked in all output
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Variables (or `*` patterns) every task inherits from rush's environment
+    /// and caches on, including isolated tasks
+    #[serde(default)]
+    pub env_passthrough: Vec<String>,
+    /// Run tasks with only declared and passed-through variables by default
+    #[serde(default)]
+    pub isolated: bool,
+    /// Default shell for tasks that don't set their own
+    #[serde(default)]
+    pub shell: Option<Shell>,
+    /// Shared caches consulted when `.rush-cache` misses
+    #[serde(default)]
+    pub cache: CacheConfig,
+    /// Refuse to start while another rush is running in the same checkout
+    #[serde(default)]
+    pub run_lock: bool,
+    /// Named pools tasks hold units of while they run in parallel, like
+    /// `db: 1` for a database only one task may use at a time
+    #[serde(default)]
+    pub resources: HashMap<String, u64>,
+    #[serde(default)]
     pub tasks: HashMap<String, Task>,
+    /// File the config was loaded from
+    #[serde(skip)]
+    pub source: PathBuf,
+    /// File each of `vars` was defined in, which differs once includes are merged
+    #[serde(skip)]
+    pub var_sources: HashMap<String, PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub backends: Vec<BackendConfig>,
+    /// zstd level for stored outputs, 0 to store them uncompressed
+    #[serde(default)]
+    pub compression: Option<i32>,
+    /// How cached outputs are put back into the workspace
+    #[serde(default)]
+    pub restore: RestoreMode,
+}
+
+impl CacheConfig {
+    fn validate(&self, source: &Path) -> Result<()> {
+        let compression = self.compression.unwrap_or(DEFAULT_COMPRESSION);
+        if self.restore == RestoreMode::Hardlink && compression != 0 {
+            bail!(
+                "cache.restore: hardlink in {} needs cache.compression: 0, since compressed outputs can only be copied",
+                source.display()
+            );
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Task {
+    #[serde(default)]
     pub cmd: String,
+    /// Steps run in order instead of a single `cmd`
+    #[serde(default)]
+    pub cmds: Vec<StepConfig>,
     #[serde(default)]
     pub deps: Vec<String>,
     #[serde(default)]
     pub cache: Vec<String>,
+    /// Files the task produces, used by `freshness: mtime`
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    #[serde(default)]
+    pub freshness: Freshness,
     #[serde(default)]
     pub env: HashMap<String, String>,
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub env_file: Vec<String>,
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// Extra variables passed through from rush's environment, on top of the
+    /// global list
+    #[serde(default)]
+    pub env_passthrough: Vec<String>,
+    /// Overrides the global `isolated` setting
+    #[serde(default)]
+    pub isolated: Option<bool>,
+    /// Run the task's commands in Linux namespaces with the project read-only
+    #[serde(default)]
+    pub sandbox: Option<SandboxConfig>,
+    /// Memory, CPU and open file limits for the task's commands
+    #[serde(default)]
+    pub limits: Limits,
+    /// Never run the task in two rush processes at once; one that has to
+    /// wait reuses the other's successful run
+    #[serde(default)]
+    pub exclusive: bool,
+    /// How many `--jobs` slots the task takes while it runs, 1 by default
+    #[serde(default)]
+    pub weight: Option<u64>,
+    /// Units of the global `resources` the task holds while it runs; a list
+    /// takes one unit of each
+    #[serde(default, deserialize_with = "resource_amounts")]
+    pub resources: HashMap<String, u64>,
+    /// Working directory, relative to the file the task is defined in
+    #[serde(default)]
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub shell: Option<Shell>,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Condition that must hold for the task to run, e.g. `os == "linux"`
+    #[serde(default, alias = "if")]
+    pub when: Option<String>,
+    /// Commands that all exit 0 when the task is already up to date
+    #[serde(default)]
+    pub status: Vec<String>,
+    #[serde(default)]
+    pub params: HashMap<String, Param>,
+    /// File the task was defined in, filled in while loading
+    #[serde(skip)]
+    pub source: PathBuf,
+}
+
+/// One step of a multi-step task: a shell command, a reference to another
+/// task, or an inline script run by an interpreter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StepConfig {
+    Command(String),
+    Detailed(StepDetail),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepDetail {
+    #[serde(default)]
+    pub cmd: Option<String>,
+    #[serde(default)]
+    pub task: Option<String>,
+    #[serde(default)]
+    pub script: Option<String>,
+    #[serde(default)]
+    pub interpreter: Option<String>,
+    #[serde(default)]
+    pub ignore_error: bool,
+}
+
+impl StepConfig {
+    fn validate(&self, task_name: &str, index: usize) -> Result<()> {
+        let StepConfig::Detailed(step) = self else {
+            return Ok(());
+        };
+
+        let kinds = [&step.cmd, &step.task, &step.script]
+            .iter()
+            .filter(|kind| kind.is_some())
+            .count();
+        if kinds != 1 {
+            bail!(
+                "Step {} of task '{}' must set exactly one of cmd, task or script",
+                index + 1,
+                task_name
+            );
+        }
+        if step.interpreter.is_some() && step.script.is_none() {
+            bail!(
+                "Step {} of task '{}' sets an interpreter without a script",
+                index + 1,
+                task_name
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Short description of the step for listings
+    pub fn summary(&self) -> String {
+        match self {
+            StepConfig::Command(cmd) => cmd.clone(),
+            StepConfig::Detailed(step) => match (&step.cmd, &step.task, &step.interpreter) {
+                (Some(cmd), _, _) => cmd.clone(),
+                (None, Some(task), _) => format!("task: {task}"),
+                (None, None, Some(interpreter)) => format!("{interpreter} script"),
+                (None, None, None) => "script".to_string(),
+            },
+        }
+    }
+}
+
+impl Task {
+    /// The task's command, or its steps joined with `&&`
+    pub fn command_summary(&self) -> String {
+        if self.cmds.is_empty() {
+            self.cmd.clone()
+        } else {
+            self.cmds
+                .iter()
+                .map(StepConfig::summary)
+                .collect::<Vec<_>>()
+                .join(" && ")
+        }
+    }
+}
+
+/// `sandbox: true`, or `sandbox: { network: true }` to keep the network
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SandboxConfig {
+    Enabled(bool),
+    Detailed(SandboxDetail),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SandboxDetail {
+    #[serde(default)]
+    pub network: bool,
+}
+
+impl SandboxConfig {
+    /// The sandbox to run in, if any
+    pub fn settings(&self) -> Option<Sandbox> {
+        match self {
+            SandboxConfig::Enabled(false) => None,
+            SandboxConfig::Enabled(true) => Some(Sandbox { network: false }),
+            SandboxConfig::Detailed(detail) => Some(Sandbox {
+                network: detail.network,
+            }),
+        }
+    }
+}
+
+/// Resources a task's commands may use, enforced with a cgroup where one
+/// can be created and with rlimits otherwise
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Maximum memory, like `512MB` or `2G`
+    #[serde(default)]
+    pub memory: Option<String>,
+    /// Relative CPU share, 1024 being the default weight
+    #[serde(default)]
+    pub cpu_shares: Option<u64>,
+    /// Maximum number of open files
+    #[serde(default)]
+    pub nofile: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self == &Limits::default()
+    }
+
+    fn validate(&self, task_name: &str) -> Result<()> {
+        if let Some(memory) = &self.memory {
+            let bytes = parse_size(memory)
+                .with_context(|| format!("Invalid memory limit for task '{task_name}'"))?;
+            if bytes == 0 {
+                bail!("Memory limit of task '{}' must be above zero", task_name);
+            }
+        }
+        if let Some(shares) = self.cpu_shares {
+            if !(2..=262144).contains(&shares) {
+                bail!(
+                    "cpu_shares of task '{}' must be between 2 and 262144, got {}",
+                    task_name,
+                    shares
+                );
+            }
+        }
+        if self.nofile == Some(0) {
+            bail!("nofile limit of task '{}' must be above zero", task_name);
+        }
+        Ok(())
+    }
+}
+
+/// How a task decides its `cache` inputs are unchanged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Freshness {
+    /// Hash the content of every input on every check. The default, since
+    /// it is the only strategy that doesn't trust file metadata
+    #[default]
+    Hash,
+    /// Compare the newest input modification time against the oldest output
+    Mtime,
+    /// Hash content, but only re-read inputs whose size, modification time or
+    /// inode changed since they were last hashed
+    Hybrid,
+}
+
+/// A named argument a task accepts on the command line (`rush deploy env=prod`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Param {
+    #[serde(default)]
+    pub default: Option<Scalar>,
+    #[serde(default, rename = "type")]
+    pub param_type: ParamType,
+    #[serde(default)]
+    pub allowed: Vec<Scalar>,
     #[serde(default)]
     pub description: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    #[default]
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+/// A YAML scalar that is used as a string, so `default: 3` and `default: "3"` are equivalent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Scalar {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl std::fmt::Display for Scalar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scalar::Bool(value) => write!(f, "{value}"),
+            Scalar::Int(value) => write!(f, "{value}"),
+            Scalar::Float(value) => write!(f, "{value}"),
+            Scalar::String(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+impl std::fmt::Display for ParamType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ParamType::String => "string",
+            ParamType::Int => "int",
+            ParamType::Float => "float",
+            ParamType::Bool => "bool",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Accept either a single string or a list of strings
+fn string_or_list<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// Whether two paths name the same file, however they were spelled
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn resource_amounts<'de, D>(deserializer: D) -> std::result::Result<HashMap<String, u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NamesOrAmounts {
+        Names(Vec<String>),
+        Amounts(HashMap<String, u64>),
+    }
+
+    Ok(match NamesOrAmounts::deserialize(deserializer)? {
+        NamesOrAmounts::Names(names) => names.into_iter().map(|name| (name, 1)).collect(),
+        NamesOrAmounts::Amounts(amounts) => amounts,
+    })
+}
+
 impl RushConfig {
+    /// Directory that relative paths in a file are resolved against
+    pub fn base_dir(path: &Path) -> &Path {
+        path.parent().unwrap_or_else(|| Path::new(""))
+    }
+
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let expanded_content = Self::expand_env_vars(&content);
-        let config: RushConfig = serde_yaml::from_str(&expanded_content)?;
+        let mut include_stack = Vec::new();
+        let config = Self::load_with_includes(path.as_ref(), &mut include_stack)?;
+        config.validate_resources()?;
+        config.cache.validate(&config.source)?;
+        Ok(config)
+    }
+
+    /// Check that tasks only use declared resources, and never more of one
+    /// than exists, which would keep them from ever starting
+    fn validate_resources(&self) -> Result<()> {
+        for (name, capacity) in &self.resources {
+            if *capacity == 0 {
+                bail!("Resource '{}' must have at least one unit", name);
+            }
+        }
+
+        let mut tasks: Vec<_> = self.tasks.iter().collect();
+        tasks.sort_by_key(|(name, _)| name.as_str());
+        for (name, task) in tasks {
+            if task.weight == Some(0) {
+                bail!("Task '{}' must have a weight of at least 1", name);
+            }
+            for (resource, amount) in &task.resources {
+                let Some(capacity) = self.resources.get(resource) else {
+                    bail!(
+                        "Task '{}' uses resource '{}', which isn't declared under resources in {}",
+                        name,
+                        resource,
+                        self.source.display()
+                    );
+                };
+                if *amount == 0 || amount > capacity {
+                    bail!(
+                        "Task '{}' needs {} of resource '{}', which has {}",
+                        name,
+                        amount,
+                        resource,
+                        capacity
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut config: RushConfig = serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+        config.source = path.to_path_buf();
+        config.var_sources = config
+            .vars
+            .keys()
+            .map(|name| (name.clone(), path.to_path_buf()))
+            .collect();
+        for (name, task) in config.tasks.iter_mut() {
+            task.source = path.to_path_buf();
+
+            if task.cmd.is_empty() == task.cmds.is_empty() {
+                bail!(
+                    "Task '{}' in {} must define exactly one of cmd or cmds",
+                    name,
+                    path.display()
+                );
+            }
+            for (index, step) in task.cmds.iter().enumerate() {
+                step.validate(name, index)?;
+            }
+            task.limits.validate(name)?;
+            if task.freshness == Freshness::Mtime
+                && (task.cache.is_empty() || task.outputs.is_empty())
+            {
+                bail!(
+                    "Task '{}' in {} uses freshness: mtime, which needs both cache inputs and outputs",
+                    name,
+                    path.display()
+                );
+            }
+        }
+
         Ok(config)
     }
 
-    fn expand_env_vars(content: &str) -> String {
-        // Handle ${VAR:-default} syntax
-        let default_regex = regex::Regex::new(r"\$\{([^}]+):-([^}]*)\}").unwrap();
-        let mut expanded = default_regex
-            .replace_all(content, |caps: &regex::Captures| {
-                let var_name = &caps[1];
-                let default_value = &caps[2];
-                std::env::var(var_name).unwrap_or_else(|_| default_value.to_string())
-            })
-            .to_string();
-
-        // Handle regular ${VAR} syntax
-        let var_regex = regex::Regex::new(r"\$\{([^}]+)\}").unwrap();
-        expanded = var_regex
-            .replace_all(&expanded, |caps: &regex::Captures| {
-                let var_name = &caps[1];
-                std::env::var(var_name).unwrap_or_else(|_| format!("${{{var_name}}}"))
-            })
-            .to_string();
-
-        expanded
+    /// Load a config file and merge in the tasks of every file it includes.
+    ///
+    /// Tasks defined in the including file override included ones. Two
+    /// included files defining the same task is an error, since neither
+    /// one clearly wins.
+    /// Global settings this file sets that only take effect in the root
+    /// config, since they change how every task runs
+    fn ignored_globals(&self) -> Vec<&'static str> {
+        let settings = [
+            ("env", !self.env.is_empty()),
+            ("env_file", !self.env_file.is_empty()),
+            ("env_passthrough", !self.env_passthrough.is_empty()),
+            ("isolated", self.isolated),
+            ("shell", self.shell.is_some()),
+            ("run_lock", self.run_lock),
+            (
+                "cache",
+                !self.cache.backends.is_empty()
+                    || self.cache.compression.is_some()
+                    || self.cache.restore != RestoreMode::default(),
+            ),
+        ];
+        settings
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    fn load_with_includes(path: &Path, include_stack: &mut Vec<PathBuf>) -> Result<Self> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Config file {} not found", path.display()))?;
+        if include_stack.contains(&canonical) {
+            bail!("Circular include detected involving {}", path.display());
+        }
+        include_stack.push(canonical);
+
+        let mut config = Self::load_file(path)?;
+        let base_dir = Self::base_dir(path);
+        let mut included: HashMap<String, Task> = HashMap::new();
+        let mut included_vars: HashMap<String, (Scalar, PathBuf)> = HashMap::new();
+        let mut resource_sources: HashMap<String, PathBuf> = config
+            .resources
+            .keys()
+            .map(|name| (name.clone(), path.to_path_buf()))
+            .collect();
+
+        for include in &config.include {
+            let include_path = base_dir.join(crate::template::expand_env_vars(include));
+            if !include_path.exists() {
+                bail!(
+                    "Included file {} not found (included from {})",
+                    include_path.display(),
+                    path.display()
+                );
+            }
+
+            let included_config = Self::load_with_includes(&include_path, include_stack)?;
+            // Masking more is always safe, so secrets apply from every file
+            for secret in &included_config.secrets {
+                if !config.secrets.contains(secret) {
+                    config.secrets.push(secret.clone());
+                }
+            }
+            let ignored = included_config.ignored_globals();
+            if !ignored.is_empty() {
+                eprintln!(
+                    "⚠️  Ignoring global {} in {}: only those of {} apply to all tasks; set them per task in included files",
+                    ignored.join(", "),
+                    include_path.display(),
+                    path.display()
+                );
+            }
+            // Pools are shared by every task, wherever they are declared
+            for (name, capacity) in included_config.resources {
+                match config.resources.get(&name) {
+                    Some(existing) if *existing != capacity => bail!(
+                        "Resource '{}' has {} units in {} but {} in {}",
+                        name,
+                        existing,
+                        resource_sources[&name].display(),
+                        capacity,
+                        include_path.display()
+                    ),
+                    Some(_) => {}
+                    None => {
+                        resource_sources.insert(name.clone(), include_path.clone());
+                        config.resources.insert(name, capacity);
+                    }
+                }
+            }
+            for (name, task) in included_config.tasks {
+                if let Some(existing) = included.get(&name) {
+                    // Reached through two includes of the same file
+                    if same_file(&existing.source, &task.source) {
+                        continue;
+                    }
+                    bail!(
+                        "Task '{}' is defined in both {} and {}; define it in {} to override both",
+                        name,
+                        existing.source.display(),
+                        task.source.display(),
+                        path.display()
+                    );
+                }
+                included.insert(name, task);
+            }
+            // Variables follow the same rules as tasks
+            for (name, value) in included_config.vars {
+                let source = included_config.var_sources[&name].clone();
+                if let Some((_, existing)) = included_vars.get(&name) {
+                    if same_file(existing, &source) {
+                        continue;
+                    }
+                    bail!(
+                        "Variable '{}' is defined in both {} and {}; define it in {} to override both",
+                        name,
+                        existing.display(),
+                        source.display(),
+                        path.display()
+                    );
+                }
+                included_vars.insert(name, (value, source));
+            }
+        }
+
+        for (name, task) in included {
+            config.tasks.entry(name).or_insert(task);
+        }
+        for (name, (value, source)) in included_vars {
+            if !config.vars.contains_key(&name) {
+                config.var_sources.insert(name.clone(), source);
+                config.vars.insert(name, value);
+            }
+        }
+
+        include_stack.pop();
+        Ok(config)
     }
 
     pub fn find_config() -> Result<RushConfig> {
@@ -0,0 +1,212 @@
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::secrets;
+
+/// One layer of a task's environment. Layers are applied in order, so a
+/// variable set by a later layer overrides the same variable from an earlier one:
+///
+/// 1. the environment rush was started with
+/// 2. global `env_file` entries, in the order listed
+/// 3. the global `env` block
+/// 4. the task's `env_file` entries, in the order listed
+/// 5. the task's `env` block
+#[derive(Debug, Clone)]
+pub enum EnvLayer {
+    File(PathBuf),
+    Inline {
+        label: &'static str,
+        vars: HashMap<String, String>,
+    },
+}
+
+/// Parse a dotenv file into its variables, in file order.
+///
+/// Supports `KEY=value`, an optional `export ` prefix, `#` comments, and
+/// single-quoted (literal) or double-quoted (with `\n`, `\"` escapes) values.
+pub fn parse_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read env file {}", path.display()))?;
+    let mut vars = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            bail!(
+                "Invalid line in env file {}:{}: expected KEY=value",
+                path.display(),
+                index + 1
+            );
+        };
+
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            bail!(
+                "Invalid variable name '{}' in env file {}:{}",
+                key,
+                path.display(),
+                index + 1
+            );
+        }
+
+        vars.push((key.to_string(), parse_value(value.trim())));
+    }
+
+    Ok(vars)
+}
+
+fn parse_value(value: &str) -> String {
+    let mut chars = value.chars();
+
+    match chars.next() {
+        Some('\'') => chars.take_while(|&c| c != '\'').collect(),
+        Some('"') => {
+            let mut unescaped = String::with_capacity(value.len());
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => match chars.next() {
+                        Some('n') => unescaped.push('\n'),
+                        Some('t') => unescaped.push('\t'),
+                        Some(other) => unescaped.push(other),
+                        None => unescaped.push('\\'),
+                    },
+                    _ => unescaped.push(c),
+                }
+            }
+            unescaped
+        }
+        // Unquoted values end at an inline comment
+        _ => match value.find(" #") {
+            Some(pos) => value[..pos].trim_end().to_string(),
+            None => value.to_string(),
+        },
+    }
+}
+
+/// Label used in verbose output for variables inherited from rush's own environment
+pub const PROCESS_LAYER: &str = "process environment";
+
+/// Variables in rush's own environment whose names match `patterns`
+pub fn passthrough_vars(patterns: &[String]) -> BTreeMap<String, String> {
+    std::env::vars()
+        .filter(|(key, _)| {
+            patterns
+                .iter()
+                .any(|pattern| secrets::wildcard_match(pattern, key))
+        })
+        .collect()
+}
+
+/// Describe where a variable came from, noting when it replaced an inherited value
+pub fn describe_source(key: &str, source: &str) -> String {
+    if std::env::var_os(key).is_some() {
+        format!("{source}, overrides {PROCESS_LAYER}")
+    } else {
+        source.to_string()
+    }
+}
+
+/// Resolve a task's environment layers into the variables to set and the
+/// layer each one came from. Env file paths and inline values are rendered
+/// as templates first. A missing env file is an error unless its entry ends
+/// in `?`, which marks it optional.
+pub fn resolve_layers(
+    layers: &[EnvLayer],
+    render: impl Fn(&str) -> Result<String>,
+) -> Result<(HashMap<String, String>, HashMap<String, String>)> {
+    let mut vars = HashMap::new();
+    let mut sources = HashMap::new();
+
+    for layer in layers {
+        match layer {
+            EnvLayer::File(path) => {
+                let rendered = render(&path.to_string_lossy())?;
+                let (path, optional) = match rendered.strip_suffix('?') {
+                    Some(path) => (PathBuf::from(path), true),
+                    None => (PathBuf::from(rendered), false),
+                };
+                if !path.exists() {
+                    if optional {
+                        continue;
+                    }
+                    bail!(
+                        "Env file {} not found (add '?' to the entry to make it optional)",
+                        path.display()
+                    );
+                }
+                let label = path.display().to_string();
+                for (key, value) in parse_env_file(&path)? {
+                    sources.insert(key.clone(), label.clone());
+                    vars.insert(key, value);
+                }
+            }
+            EnvLayer::Inline {
+                label,
+                vars: inline,
+            } => {
+                for (key, value) in inline {
+                    sources.insert(key.clone(), label.to_string());
+                    vars.insert(key.clone(), render(value)?);
+                }
+            }
+        }
+    }
+
+    Ok((vars, sources))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_value_quoting() {
+        assert_eq!(parse_value("plain value"), "plain value");
+        assert_eq!(parse_value("value # comment"), "value");
+        assert_eq!(parse_value("a#b"), "a#b");
+        assert_eq!(parse_value("'lit\\n $X' # c"), "lit\\n $X");
+        assert_eq!(parse_value(r#""a\nb \"q\" \\""#), "a\nb \"q\" \\");
+        assert_eq!(parse_value(r##""# not a comment""##), "# not a comment");
+        assert_eq!(parse_value(""), "");
+    }
+
+    #[test]
+    fn parse_env_file_lines() {
+        let path = std::env::temp_dir().join(format!("rush-env-test-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# comment\n\nexport A=1\n  B = two words \nC='x=y'\nA=again\n",
+        )
+        .unwrap();
+        let vars = parse_env_file(&path);
+        fs::write(&path, "NOT A VAR\n").unwrap();
+        let invalid = parse_env_file(&path);
+        fs::write(&path, "BAD KEY=1\n").unwrap();
+        let bad_key = parse_env_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let pairs = |items: &[(&str, &str)]| -> Vec<(String, String)> {
+            items
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            vars.unwrap(),
+            pairs(&[("A", "1"), ("B", "two words"), ("C", "x=y"), ("A", "again")])
+        );
+        assert!(invalid
+            .unwrap_err()
+            .to_string()
+            .contains(":1: expected KEY=value"));
+        assert!(bad_key.is_err());
+    }
+}
@@ -1,27 +1,64 @@
 use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::Path;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::mpsc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::process::Command;
-
-use crate::cache::TaskCache;
-use crate::graph::{Task, TaskGraph};
+use tokio::task::JoinError;
+
+use crate::cache::{RunLog, TaskCache};
+use crate::cachecmd::format_age;
+use crate::config::Freshness;
+use crate::env;
+use crate::fsutil;
+use crate::graph::{Step, StepKind, Task, TaskGraph};
+use crate::limits::{self, Enforcement};
+use crate::resources::{Demand, Pools};
+use crate::sandbox::{self, Scratch};
+use crate::secrets::{self, Redactor};
+use crate::shell;
+use crate::trace::{self, StrictInputs, Undeclared};
 
 pub struct TaskExecutor {
     graph: TaskGraph,
     cache: TaskCache,
     verbose: bool,
+    options: RunOptions,
+    redactor: Redactor,
+}
+
+/// Command-line switches that change how tasks run
+#[derive(Debug, Clone, Copy)]
+pub struct RunOptions {
+    /// Job slots shared by tasks running in parallel, which take `weight`
+    /// slots each
+    pub jobs: u64,
+    /// Print the stored output of tasks restored from the cache
+    pub replay: bool,
+    /// Trace tasks with `cache` inputs for files they don't declare
+    pub strict_inputs: Option<StrictInputs>,
 }
 
 impl TaskExecutor {
-    pub fn new(graph: TaskGraph, verbose: bool) -> Self {
+    pub fn new(
+        graph: TaskGraph,
+        cache: TaskCache,
+        verbose: bool,
+        options: RunOptions,
+        redactor: Redactor,
+    ) -> Self {
         Self {
             graph,
-            cache: TaskCache::new(),
+            cache,
             verbose,
+            options,
+            redactor,
         }
     }
 
@@ -48,213 +85,640 @@ impl TaskExecutor {
         if self.verbose {
             println!("🔍 Debug: Task dependency resolution");
             println!("   Target task: {task_name}");
-            println!("   Execution order: {}", execution_order.join(" -> "));
+            println!(
+                "   Execution order: {}",
+                self.describe_order(&execution_order)
+            );
             println!("   Total tasks to run: {}", execution_order.len());
+            for name in &execution_order {
+                if let Some(reason) = self.skip_reason(name) {
+                    println!("   Skipping {name} ({reason})");
+                }
+            }
         } else {
-            println!("Execution order: {}", execution_order.join(" -> "));
+            println!("Execution order: {}", self.describe_order(&execution_order));
         }
 
+        let mut outcomes = Vec::with_capacity(execution_order.len());
+
         for task_name in execution_order {
             if let Some(task) = self.graph.tasks.get(&task_name) {
                 if self.verbose {
                     println!("🔍 Debug: About to execute task '{}'", task.name);
-                    println!("   Command: {}", task.cmd);
-                    if !task.env.is_empty() {
-                        println!("   Environment: {:?}", task.env);
+                    self.print_steps(task);
+                    let passthrough = env::passthrough_vars(&task.env_passthrough);
+                    if task.isolated {
+                        println!("   Isolated: only the variables below are set");
+                    }
+                    if let Some(sandbox) = task.sandbox {
+                        let network = if sandbox.network { "on" } else { "off" };
+                        println!("   Sandboxed: project read-only, network {network}");
+                    }
+                    if !task.limits.is_empty() {
+                        let limits = [
+                            task.limits
+                                .memory
+                                .as_ref()
+                                .map(|size| format!("memory {size}")),
+                            task.limits
+                                .cpu_shares
+                                .map(|shares| format!("cpu_shares {shares}")),
+                            task.limits.nofile.map(|files| format!("nofile {files}")),
+                        ];
+                        let limits: Vec<_> = limits.into_iter().flatten().collect();
+                        println!("   Limits: {}", limits.join(", "));
+                    }
+                    if !task.env.is_empty() || !passthrough.is_empty() {
+                        println!("   Environment:");
+                        let mut vars: Vec<_> = passthrough
+                            .iter()
+                            .filter(|(key, _)| !task.env.contains_key(*key))
+                            .chain(&task.env)
+                            .collect();
+                        vars.sort_by_key(|(key, _)| *key);
+                        for (key, value) in vars {
+                            let value = if task.is_secret(key) {
+                                secrets::MASK.to_string()
+                            } else {
+                                self.redactor.redact(value)
+                            };
+                            if !task.env.contains_key(key) {
+                                println!("     {key}={value} (passed through)");
+                                continue;
+                            }
+                            let source = task
+                                .env_sources
+                                .get(key)
+                                .map(String::as_str)
+                                .unwrap_or("task env");
+                            println!("     {key}={value} ({})", env::describe_source(key, source));
+                        }
                     }
                 }
-                self.run_single_task(task)
+                let outcome = self
+                    .run_single_task(task)
                     .await
                     .with_context(|| format!("Task '{}' failed during execution", task.name))?;
+                outcomes.push((task_name, outcome));
             }
         }
 
+        print_summary(&outcomes);
         Ok(())
     }
 
+    /// Execution order with tasks whose `when` condition is false marked
+    fn describe_order(&self, execution_order: &[String]) -> String {
+        execution_order
+            .iter()
+            .map(|name| match self.skip_reason(name) {
+                Some(_) => format!("{name} (skipped)"),
+                None => name.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+
+    fn skip_reason(&self, task_name: &str) -> Option<&str> {
+        self.graph
+            .tasks
+            .get(task_name)
+            .and_then(|task| task.skip_reason.as_deref())
+    }
+
+    /// Run the task and its dependencies, starting each one as soon as its
+    /// dependencies are done and the job slots and resources it needs are free
     pub async fn execute_task_parallel(&self, task_name: &str) -> Result<()> {
         let execution_order = self.graph.topological_sort(task_name)?;
 
         println!(
             "🚀 Parallel execution order: {}",
-            execution_order.join(" -> ")
+            self.describe_order(&execution_order)
         );
+        let mut outcomes = Vec::with_capacity(execution_order.len());
 
-        // Group tasks by dependency level for parallel execution
-        let levels = self.build_execution_levels(&execution_order)?;
-
-        for (level, tasks) in levels.iter().enumerate() {
-            if tasks.len() == 1 {
-                // Single task - run normally
-                if let Some(task) = self.graph.tasks.get(&tasks[0]) {
-                    let task_progress = ProgressBar::new_spinner();
-                    task_progress.set_style(
-                        ProgressStyle::default_spinner()
-                            .template("🏃 {msg} {spinner:.green}")
-                            .unwrap(),
-                    );
-                    task_progress.set_message(format!("Running {}", task.name));
+        // Start the tasks on the longest remaining path first, since
+        // they bound how soon the run can finish
+        let priorities = self
+            .graph
+            .critical_paths(&execution_order, &self.cache.durations());
+        let mut pending = execution_order;
+        pending.sort_by_key(|name| std::cmp::Reverse(priorities.get(name).copied()));
+        if self.verbose {
+            let order: Vec<_> = pending
+                .iter()
+                .map(|name| format!("{} ({:.1}s)", name, priorities[name].as_secs_f64()))
+                .collect();
+            println!(
+                "🔍 Debug: Start priority by remaining critical path: {}",
+                order.join(", ")
+            );
+        }
 
-                    self.run_single_task_with_progress(task, &task_progress)
-                        .await?;
-                    task_progress.finish_with_message(format!("✅ {} completed", task.name));
-                }
-            } else {
-                // Multiple independent tasks - run in parallel
-                println!(
-                    "📦 Level {}: Running {} tasks in parallel",
-                    level,
-                    tasks.len()
-                );
-
-                let mut handles = Vec::new();
-
-                for task_name in tasks {
-                    if let Some(task) = self.graph.tasks.get(task_name) {
-                        let task = task.clone();
-                        let cache = TaskCache::new();
-                        let task_progress = ProgressBar::new_spinner();
-                        task_progress.set_style(
-                            ProgressStyle::default_spinner()
-                                .template("🏃 {msg} {spinner:.green}")
-                                .unwrap(),
-                        );
-                        task_progress.set_message(format!("Running {}", task.name));
-
-                        let handle = tokio::spawn(async move {
-                            let result = Self::run_task_standalone_with_progress(
-                                &task,
-                                &cache,
-                                &task_progress,
-                            )
-                            .await;
-                            if result.is_ok() {
-                                task_progress
-                                    .finish_with_message(format!("✅ {} completed", task.name));
-                            } else {
-                                task_progress
-                                    .finish_with_message(format!("❌ {} failed", task.name));
-                            }
-                            result
-                        });
+        let mut pools = Pools::new(self.options.jobs, &self.graph.resources);
+        let mut done = HashSet::new();
+        let mut running = FuturesUnordered::new();
+        let mut failure = None;
 
-                        handles.push((task_name.clone(), handle));
+        loop {
+            // Start ready tasks by priority; one that doesn't fit doesn't
+            // hold back lower ones that do
+            if failure.is_none() {
+                let mut index = 0;
+                while index < pending.len() {
+                    let name = &pending[index];
+                    let ready = self
+                        .graph
+                        .dependencies
+                        .get(name)
+                        .is_none_or(|deps| deps.iter().all(|dep| done.contains(dep)));
+                    let Some(task) = self.graph.tasks.get(name).filter(|_| ready) else {
+                        index += 1;
+                        continue;
+                    };
+                    let demand = pools.demand(task);
+                    if !pools.try_take(&demand) {
+                        index += 1;
+                        continue;
                     }
+
+                    pending.remove(index);
+                    running.push(self.spawn_task(task, demand));
                 }
+            }
 
-                // Wait for all parallel tasks to complete
-                for (task_name, handle) in handles {
-                    match handle.await {
-                        Ok(Ok(())) => {
-                            // Task completed successfully - already logged in run_task_standalone
-                        }
-                        Ok(Err(e)) => {
-                            return Err(anyhow::anyhow!("Task '{}' failed: {}", task_name, e));
-                        }
-                        Err(e) => {
-                            return Err(anyhow::anyhow!("Task '{}' panicked: {}", task_name, e));
-                        }
-                    }
+            let Some((task_name, demand, result)) = running.next().await else {
+                break;
+            };
+            pools.release(&demand);
+            match result {
+                Ok(Ok(outcome)) => {
+                    done.insert(task_name.clone());
+                    outcomes.push((task_name, outcome));
+                }
+                // Let running tasks finish, so none is cut off halfway
+                // through writing its outputs
+                Ok(Err(e)) => {
+                    failure.get_or_insert_with(|| {
+                        anyhow::anyhow!("Task '{}' failed: {}", task_name, e)
+                    });
+                }
+                Err(e) => {
+                    failure.get_or_insert_with(|| {
+                        anyhow::anyhow!("Task '{}' panicked: {}", task_name, e)
+                    });
                 }
             }
         }
 
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        if !pending.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Dependency cycle detected or invalid state"
+            ));
+        }
+
+        print_summary(&outcomes);
         Ok(())
     }
 
-    fn build_execution_levels(&self, execution_order: &[String]) -> Result<Vec<Vec<String>>> {
-        let mut levels = Vec::new();
-        let mut completed = std::collections::HashSet::new();
-        let mut remaining: Vec<String> = execution_order.to_vec();
+    /// Run a task on its own tokio task with a spinner, handing back its
+    /// demand so the scheduler can release it
+    fn spawn_task(
+        &self,
+        task: &Task,
+        demand: Demand,
+    ) -> impl Future<Output = (String, Demand, Result<Result<TaskOutcome>, JoinError>)> {
+        let task = task.clone();
+        let cache = self.cache.clone();
+        let redactor = self.redactor.clone();
+        let root = self.graph.root_dir.clone();
+        let options = self.options;
+        let task_progress = ProgressBar::new_spinner();
+        task_progress.set_style(
+            ProgressStyle::default_spinner()
+                .template("🏃 {msg} {spinner:.green}")
+                .unwrap(),
+        );
+        task_progress.set_message(format!("Running {}", task.name));
+
+        let task_name = task.name.clone();
+        let handle = tokio::spawn(async move {
+            let start_time = Instant::now();
+            let result = Self::run_task(
+                &task,
+                &cache,
+                &redactor,
+                &root,
+                options,
+                Reporter::Progress(&task_progress),
+            )
+            .await;
+            match &result {
+                Ok(outcome) => {
+                    outcome.record_duration(&cache, &task.name, start_time);
+                    task_progress.finish_with_message(outcome.finish_message(&task.name))
+                }
+                Err(_) => task_progress.finish_with_message(format!("❌ {} failed", task.name)),
+            }
+            result
+        });
+
+        async move { (task_name, demand, handle.await) }
+    }
+
+    fn print_steps(&self, task: &Task) {
+        match task.steps.as_slice() {
+            [Step {
+                kind: StepKind::Command(cmd),
+                ..
+            }] => println!("   Command: {}", self.redactor.redact(cmd)),
+            steps => {
+                println!("   Steps:");
+                for (index, step) in steps.iter().enumerate() {
+                    let ignored = if step.ignore_error {
+                        " (errors ignored)"
+                    } else {
+                        ""
+                    };
+                    println!(
+                        "     {}. {}{}",
+                        index + 1,
+                        self.redactor.redact(&step.label()),
+                        ignored
+                    );
+                }
+            }
+        }
+    }
+
+    async fn run_single_task(&self, task: &Task) -> Result<TaskOutcome> {
+        let start_time = Instant::now();
+        let outcome = Self::run_task(
+            task,
+            &self.cache,
+            &self.redactor,
+            &self.graph.root_dir,
+            self.options,
+            Reporter::Console,
+        )
+        .await?;
+        outcome.record_duration(&self.cache, &task.name, start_time);
+        Ok(outcome)
+    }
+
+    /// Run a task's steps unless its cache is fresh, reporting progress as
+    /// plain lines or on a spinner
+    async fn run_task(
+        task: &Task,
+        cache: &TaskCache,
+        redactor: &Redactor,
+        root: &Path,
+        options: RunOptions,
+        reporter: Reporter<'_>,
+    ) -> Result<TaskOutcome> {
+        let start_time = Instant::now();
+
+        if let Some(reason) = &task.skip_reason {
+            reporter.skipped(task, reason);
+            return Ok(TaskOutcome::Skipped(reason.clone()));
+        }
+
+        // Another rush running this task in the same checkout would race on
+        // its outputs and cache entries; wait for it, then likely hit its result
+        let mut waited_since = None;
+        let _lock = if task.cache_files.is_empty() && !task.exclusive {
+            None
+        } else {
+            match cache.try_lock_task(&task.name)? {
+                Some(lock) => Some(lock),
+                None => {
+                    reporter.waiting(task);
+                    waited_since = Some(SystemTime::now());
+                    Some(tokio::task::block_in_place(|| cache.lock_task(&task.name))?)
+                }
+            }
+        };
 
-        while !remaining.is_empty() {
-            let mut ready_tasks = Vec::new();
+        // An exclusive task the other rush just ran the same way needn't run
+        // again, cached or not
+        let run_hash = if task.exclusive {
+            Some(tokio::task::block_in_place(|| cache.run_hash(task))?)
+        } else {
+            None
+        };
+        if let (Some(since), Some(run_hash)) = (waited_since, &run_hash) {
+            if cache.ran_since(&task.name, run_hash, since) {
+                reporter.cached(task, "just ran in another rush");
+                return Ok(TaskOutcome::Cached);
+            }
+        }
 
-            // Find tasks that can run now (all dependencies completed)
-            for task_name in &remaining {
-                if let Some(deps) = self.graph.dependencies.get(task_name) {
-                    if deps.iter().all(|dep| completed.contains(dep)) {
-                        ready_tasks.push(task_name.clone());
+        let mut input_hash = None;
+        if task.freshness == Freshness::Mtime {
+            if cache.outputs_up_to_date(&task.cache_files, &task.outputs)? {
+                reporter.cached(task, "up to date");
+                return Ok(TaskOutcome::Cached);
+            }
+        } else if !task.cache_files.is_empty() {
+            // Check cache if cache files are specified. Hashing reads files on
+            // its own threads, so keep it off the async workers.
+            let inputs = tokio::task::block_in_place(|| cache.compute_task_hash(task))?;
+            let hit = tokio::task::block_in_place(|| cache.is_cached(&task.name, &inputs.hash));
+            if let Some(entry) = hit {
+                cache.record(&task.name, true);
+                reporter.cached(task, "cached");
+                if options.replay {
+                    match cache.run_log(&entry) {
+                        Ok(log) => reporter.replayed(task, &log),
+                        Err(e) => eprintln!("⚠️  Can't replay output of '{}': {e:#}", task.name),
                     }
-                } else {
-                    // No dependencies
-                    ready_tasks.push(task_name.clone());
                 }
+                return Ok(TaskOutcome::Cached);
             }
+            let remote = tokio::task::block_in_place(|| cache.fetch_remote(task, &inputs));
+            if let Some(backend) = remote {
+                cache.record(&task.name, true);
+                reporter.cached(task, &format!("cached in {backend}"));
+                return Ok(TaskOutcome::Cached);
+            }
+            cache.record(&task.name, false);
+            input_hash = Some(inputs);
+        }
+
+        if Self::is_up_to_date(task).await? {
+            reporter.cached(task, "up to date");
+            return Ok(TaskOutcome::Cached);
+        }
+
+        if !task.cache_files.is_empty() {
+            tokio::task::block_in_place(|| cache.detach_outputs(&task.outputs))?;
+        }
 
-            if ready_tasks.is_empty() {
-                return Err(anyhow::anyhow!(
-                    "Dependency cycle detected or invalid state"
-                ));
+        reporter.started(task);
+
+        // Only cached tasks have declared inputs worth checking
+        let trace_log = match options.strict_inputs {
+            Some(mode) if !task.cache_files.is_empty() => {
+                Some((mode, trace::create_log(&task.name)?))
             }
+            _ => None,
+        };
 
-            // Mark ready tasks as completed and remove from remaining
-            for task in &ready_tasks {
-                completed.insert(task.clone());
+        let mut output = TaskOutput::default();
+        let trace = trace_log.as_ref().map(|(_, log)| log.as_path());
+        let mut result = Self::run_steps(task, reporter, redactor, trace, &mut output).await;
+        let elapsed = start_time.elapsed();
+
+        if let Some((mode, log)) = trace_log {
+            let accesses = trace::Accesses::load(&log);
+            let _ = std::fs::remove_file(&log);
+            if result.is_ok() {
+                let undeclared = accesses.and_then(|accesses| {
+                    // Traced paths have symlinks resolved
+                    let root = std::fs::canonicalize(root).with_context(|| {
+                        format!("Failed to resolve project directory {}", root.display())
+                    })?;
+                    trace::undeclared(&accesses, &root, &task.cache_files, &task.outputs)
+                })?;
+                if !undeclared.is_empty() {
+                    reporter.undeclared(task, &undeclared);
+                    if mode == StrictInputs::Fail {
+                        result = Err(anyhow::anyhow!(
+                            "Task '{}' used files it doesn't declare (--strict-inputs=fail)",
+                            task.name
+                        ));
+                    }
+                }
             }
-            remaining.retain(|task| !ready_tasks.contains(task));
+        }
+
+        match result {
+            Ok(()) => {
+                reporter.succeeded(task, elapsed, &output, redactor);
+
+                // Cache under the inputs the task actually ran with; if it
+                // rewrote them, the next run sees a new hash and runs again
+                if let Some(inputs) = input_hash {
+                    // Secrets are masked before the output is written to disk
+                    let log = RunLog {
+                        stdout: redactor
+                            .redact(&String::from_utf8_lossy(&output.stdout))
+                            .into_bytes(),
+                        stderr: redactor
+                            .redact(&String::from_utf8_lossy(&output.stderr))
+                            .into_bytes(),
+                        created: None,
+                        duration: Some(elapsed),
+                    };
+                    tokio::task::block_in_place(|| -> Result<()> {
+                        cache.mark_cached(&task.name, &inputs, &task.outputs, Some(&log))?;
+                        cache.upload(task, &inputs);
+                        Ok(())
+                    })?;
+                }
 
-            levels.push(ready_tasks);
+                // Recorded under the lock, before waiting processes get it
+                if let Some(run_hash) = &run_hash {
+                    cache.record_run(&task.name, run_hash);
+                }
+
+                Ok(TaskOutcome::Ran)
+            }
+            Err(e) => {
+                reporter.failed(task, &output, redactor);
+                Err(e)
+            }
         }
+    }
+
+    /// Run steps in order, stopping at the first failure that isn't ignored
+    fn run_steps<'a>(
+        task: &'a Task,
+        reporter: Reporter<'a>,
+        redactor: &'a Redactor,
+        trace: Option<&'a Path>,
+        output: &'a mut TaskOutput,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let total = task.steps.len();
+
+            for (index, step) in task.steps.iter().enumerate() {
+                let label = match &step.kind {
+                    StepKind::Inline(inlined) => match &inlined.skip_reason {
+                        Some(reason) => format!("{} (skipped, {reason})", step.label()),
+                        None => step.label(),
+                    },
+                    _ => step.label(),
+                };
+                if total > 1 {
+                    reporter.step(task, index, total, &redactor.redact(&label));
+                }
 
-        Ok(levels)
+                let result = match &step.kind {
+                    StepKind::Inline(inlined) if inlined.skip_reason.is_some() => Ok(()),
+                    StepKind::Inline(inlined) => {
+                        Self::run_steps(inlined, reporter, redactor, trace, output).await
+                    }
+                    kind => Self::run_command_step(task, kind, trace, output).await,
+                };
+
+                match result {
+                    Ok(()) => {}
+                    Err(e) if step.ignore_error => {
+                        reporter.step_error_ignored(task, index, total, &e, redactor);
+                    }
+                    Err(e) if total > 1 => {
+                        return Err(e.context(format!(
+                            "Task '{}' failed at step {}/{} ({})",
+                            task.name,
+                            index + 1,
+                            total,
+                            redactor.redact(&step.label())
+                        )));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            Ok(())
+        })
     }
 
-    async fn run_single_task(&self, task: &Task) -> Result<()> {
-        // Check cache if cache files are specified
-        if !task.cache_files.is_empty() {
-            let hash = self
-                .cache
-                .compute_task_hash(&task.name, &task.cache_files)?;
-            if self.cache.is_cached(&task.name, &hash) {
-                println!("⚡ Task '{}' skipped (cached)", task.name);
-                return Ok(());
+    /// Whether every `status` command of the task succeeds
+    async fn is_up_to_date(task: &Task) -> Result<bool> {
+        if task.status.is_empty() {
+            return Ok(false);
+        }
+
+        for check in &task.status {
+            let mut cmd = task
+                .shell
+                .command(check)
+                .with_context(|| format!("Invalid status command for task '{}'", task.name))?;
+            let _guard = Self::apply_task_context(task, &mut cmd)?;
+            cmd.stdout(Stdio::null()).stderr(Stdio::null());
+
+            let status = cmd.status().await;
+            let status = match (status, task.sandbox) {
+                (Err(e), Some(_)) => return Err(sandbox::spawn_error(&task.name, e)),
+                (status, _) => status.with_context(|| {
+                    format!("Failed to run status check for task '{}'", task.name)
+                })?,
+            };
+            if !status.success() {
+                return Ok(false);
             }
         }
 
-        println!("🏃 Running task: {}", task.name);
+        Ok(true)
+    }
 
-        let mut cmd = if cfg!(target_os = "windows") {
-            let mut cmd = Command::new("cmd");
-            cmd.args(["/C", &task.cmd]);
-            cmd
-        } else {
-            let mut cmd = Command::new("sh");
-            cmd.args(["-c", &task.cmd]);
-            cmd
-        };
+    /// Apply the task's working directory, environment, limits and sandbox
+    /// to a command. The returned guard must outlive the command.
+    fn apply_task_context(task: &Task, cmd: &mut Command) -> Result<CommandGuard> {
+        if let Some(dir) = &task.dir {
+            cmd.current_dir(dir);
+        }
+
+        // Isolated tasks only see what the cache key covers
+        if task.isolated {
+            cmd.env_clear();
+            cmd.envs(env::passthrough_vars(&task.env_passthrough));
+        }
 
         // Set environment variables
         for (key, value) in &task.env {
             cmd.env(key, value);
         }
 
-        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-
-        let output = cmd.output().await?;
+        // Limits first, so the sandbox's namespaces are created inside the
+        // task's cgroup
+        let limits = if task.limits.is_empty() {
+            None
+        } else {
+            Some(limits::apply(cmd, &task.name, &task.limits)?)
+        };
+        let scratch = task
+            .sandbox
+            .map(|settings| sandbox::apply(cmd, task, settings))
+            .transpose()?;
+
+        Ok(CommandGuard {
+            limits,
+            _scratch: scratch,
+        })
+    }
 
-        if output.status.success() {
-            println!("✅ Task '{}' completed successfully", task.name);
-            if !output.stdout.is_empty() {
-                println!("{}", String::from_utf8_lossy(&output.stdout));
+    /// Run one command or script, through the tracer if `trace` names a log
+    async fn run_command_step(
+        task: &Task,
+        kind: &StepKind,
+        trace: Option<&Path>,
+        output: &mut TaskOutput,
+    ) -> Result<()> {
+        let mut script_file = None;
+        let mut cmd = match kind {
+            StepKind::Command(command) => task
+                .shell
+                .command(command)
+                .with_context(|| format!("Invalid command for task '{}'", task.name))?,
+            StepKind::Script {
+                interpreter,
+                source,
+            } => {
+                let extension = match interpreter {
+                    Some(interpreter) => shell::interpreter_extension(interpreter),
+                    None => task.shell.script_extension(),
+                };
+                let path = write_script(&task.name, source, extension)?;
+                let cmd = match interpreter {
+                    Some(interpreter) => {
+                        let mut cmd = Command::new(interpreter);
+                        cmd.arg(&path);
+                        cmd
+                    }
+                    None => task.shell.script_command(&path),
+                };
+                script_file = Some(path);
+                cmd
             }
+            StepKind::Task(_) | StepKind::Inline(_) => unreachable!("resolved by run_steps"),
+        };
 
-            // Cache the result if cache files are specified
-            if !task.cache_files.is_empty() {
-                let hash = self
-                    .cache
-                    .compute_task_hash(&task.name, &task.cache_files)?;
-                self.cache.mark_cached(&task.name, &hash)?;
-            }
-        } else {
-            println!("❌ Task '{}' failed", task.name);
-            if !output.stderr.is_empty() {
-                eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        if let Some(log) = trace {
+            cmd = trace::wrap(&cmd, log)?;
+        }
+        let result = match Self::apply_task_context(task, &mut cmd) {
+            Ok(guard) => {
+                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+                match (cmd.output().await, task.sandbox) {
+                    (Err(e), Some(_)) => Err(sandbox::spawn_error(&task.name, e)),
+                    (result, _) => result
+                        .with_context(|| format!("Failed to start task '{}'", task.name))
+                        .map(|output| (output, guard)),
+                }
             }
+            Err(e) => Err(e),
+        };
+        if let Some(path) = script_file {
+            let _ = std::fs::remove_file(path);
+        }
+        let (result, guard) = result?;
+
+        output.stdout.extend_from_slice(&result.stdout);
+        output.stderr.extend_from_slice(&result.stderr);
+
+        if let Some(reason) = guard.limits.and_then(|limits| limits.exceeded(&result)) {
+            anyhow::bail!("Task '{}' {}", task.name, reason);
+        }
+        if !result.status.success() {
             anyhow::bail!(
                 "Task '{}' failed with exit code: {:?}",
                 task.name,
-                output.status.code()
+                result.status.code()
             );
         }
 
@@ -298,15 +762,17 @@ impl TaskExecutor {
 
                     println!("\n🔄 File change detected, re-running task: {task_name}");
 
-                    // Clear cache to force rebuild
-                    let _ = std::fs::remove_dir_all(".rush-cache");
+                    // Clear cache entries to force rebuild
+                    for name in self.graph.topological_sort(task_name).unwrap_or_default() {
+                        let _ = self.cache.clean(Some(&name));
+                    }
 
                     if parallel {
                         if let Err(e) = self.execute_task_parallel(task_name).await {
-                            eprintln!("❌ Task failed: {e}");
+                            eprintln!("❌ Task failed: {}", self.redactor.redact(&e.to_string()));
                         }
                     } else if let Err(e) = self.execute_task(task_name).await {
-                        eprintln!("❌ Task failed: {e}");
+                        eprintln!("❌ Task failed: {}", self.redactor.redact(&e.to_string()));
                     }
 
                     println!("👀 Watching for more changes...");
@@ -323,138 +789,254 @@ impl TaskExecutor {
 
         Ok(())
     }
+}
 
-    async fn run_single_task_with_progress(
-        &self,
-        task: &Task,
-        progress: &ProgressBar,
-    ) -> Result<()> {
-        let start_time = Instant::now();
+/// How a task finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TaskOutcome {
+    Ran,
+    Cached,
+    Skipped(String),
+}
 
-        // Check cache if cache files are specified
-        if !task.cache_files.is_empty() {
-            let hash = self
-                .cache
-                .compute_task_hash(&task.name, &task.cache_files)?;
-            if self.cache.is_cached(&task.name, &hash) {
-                progress.set_message(format!("⚡ {} (cached)", task.name));
-                return Ok(());
-            }
+impl TaskOutcome {
+    fn finish_message(&self, task_name: &str) -> String {
+        match self {
+            TaskOutcome::Ran => format!("✅ {task_name} completed"),
+            TaskOutcome::Cached => format!("⚡ {task_name} (cached)"),
+            TaskOutcome::Skipped(reason) => format!("⏭️  {task_name} skipped ({reason})"),
         }
+    }
 
-        progress.set_message(format!("🏃 Running {}", task.name));
-
-        let mut cmd = if cfg!(target_os = "windows") {
-            let mut cmd = Command::new("cmd");
-            cmd.args(["/C", &task.cmd]);
-            cmd
-        } else {
-            let mut cmd = Command::new("sh");
-            cmd.args(["-c", &task.cmd]);
-            cmd
-        };
+    /// Remember how long the task took for the scheduler's priorities.
+    /// Only runs count: cache hits and skips say nothing about how long the
+    /// commands take.
+    fn record_duration(&self, cache: &TaskCache, task_name: &str, start_time: Instant) {
+        if *self == TaskOutcome::Ran {
+            cache.record_duration(task_name, start_time.elapsed());
+        }
+    }
+}
 
-        // Set environment variables
-        for (key, value) in &task.env {
-            cmd.env(key, value);
+fn print_summary(outcomes: &[(String, TaskOutcome)]) {
+    let count =
+        |wanted: fn(&TaskOutcome) -> bool| outcomes.iter().filter(|(_, o)| wanted(o)).count();
+    let ran = count(|o| *o == TaskOutcome::Ran);
+    let cached = count(|o| *o == TaskOutcome::Cached);
+    let skipped = count(|o| matches!(o, TaskOutcome::Skipped(_)));
+
+    println!("📊 Summary: {ran} ran, {cached} cached, {skipped} skipped");
+    for (name, outcome) in outcomes {
+        if let TaskOutcome::Skipped(reason) = outcome {
+            println!("   ⏭️  {name} skipped ({reason})");
         }
+    }
+}
 
-        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+/// Output collected from every step of a task
+#[derive(Debug, Default)]
+struct TaskOutput {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
 
-        let output = cmd.output().await?;
-        let elapsed = start_time.elapsed();
+/// Write an inline script to a private temporary file for its interpreter
+/// to run
+fn write_script(task_name: &str, source: &str, extension: &str) -> Result<PathBuf> {
+    let (path, mut file) = fsutil::create_private_temp("script", task_name, extension)?;
+    std::io::Write::write_all(&mut file, source.as_bytes())
+        .with_context(|| format!("Failed to write script for task '{task_name}'"))?;
+    Ok(path)
+}
 
-        if output.status.success() {
-            progress.set_message(format!("✅ {} ({:.1}s)", task.name, elapsed.as_secs_f32()));
+/// What a started command holds on to until it exits
+struct CommandGuard {
+    limits: Option<Enforcement>,
+    _scratch: Option<Scratch>,
+}
 
-            // Cache the result if cache files are specified
-            if !task.cache_files.is_empty() {
-                let hash = self
-                    .cache
-                    .compute_task_hash(&task.name, &task.cache_files)?;
-                self.cache.mark_cached(&task.name, &hash)?;
-            }
-        } else {
-            progress.set_message(format!("❌ {} failed", task.name));
-            if !output.stderr.is_empty() {
-                eprintln!(
-                    "Error output for {}:\n{}",
-                    task.name,
-                    String::from_utf8_lossy(&output.stderr)
-                );
+/// How a running task reports progress: plain lines when tasks run one at a
+/// time, or a spinner when they run in parallel
+#[derive(Clone, Copy)]
+enum Reporter<'a> {
+    Console,
+    Progress(&'a ProgressBar),
+}
+
+impl Reporter<'_> {
+    /// Report a task skipped because its cache is fresh or its status checks pass
+    fn cached(self, task: &Task, why: &str) {
+        match self {
+            Reporter::Console => println!("⚡ Task '{}' skipped ({why})", task.name),
+            Reporter::Progress(progress) => {
+                progress.set_message(format!("⚡ {} ({why})", task.name))
             }
-            anyhow::bail!(
-                "Task '{}' failed with exit code: {:?}",
-                task.name,
-                output.status.code()
-            );
         }
-
-        Ok(())
     }
 
-    async fn run_task_standalone_with_progress(
-        task: &Task,
-        cache: &TaskCache,
-        progress: &ProgressBar,
-    ) -> Result<()> {
-        let start_time = Instant::now();
+    /// Print a cached run's output, marked so it isn't mistaken for a new run
+    fn replayed(self, task: &Task, log: &RunLog) {
+        if log.stdout.is_empty() && log.stderr.is_empty() {
+            return;
+        }
+        let mut origin = Vec::new();
+        if let Some(created) = log.created {
+            origin.push(format!("ran {}", format_age(created)));
+        }
+        if let Some(duration) = log.duration {
+            origin.push(format!("took {:.1}s", duration.as_secs_f32()));
+        }
+        let origin = if origin.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", origin.join(", "))
+        };
 
-        // Check cache if cache files are specified
-        if !task.cache_files.is_empty() {
-            let hash = cache.compute_task_hash(&task.name, &task.cache_files)?;
-            if cache.is_cached(&task.name, &hash) {
-                progress.set_message(format!("⚡ {} (cached)", task.name));
-                return Ok(());
+        let print = || {
+            println!("📼 Output of '{}' replayed from cache{origin}:", task.name);
+            for line in String::from_utf8_lossy(&log.stdout).lines() {
+                println!("   │ {line}");
             }
+            for line in String::from_utf8_lossy(&log.stderr).lines() {
+                eprintln!("   │ {line}");
+            }
+        };
+        match self {
+            Reporter::Console => print(),
+            Reporter::Progress(progress) => progress.suspend(print),
         }
+    }
 
-        progress.set_message(format!("🏃 Running {}", task.name));
-
-        let mut cmd = if cfg!(target_os = "windows") {
-            let mut cmd = Command::new("cmd");
-            cmd.args(["/C", &task.cmd]);
-            cmd
-        } else {
-            let mut cmd = Command::new("sh");
-            cmd.args(["-c", &task.cmd]);
-            cmd
+    /// Report files a traced run used without declaring them
+    fn undeclared(self, task: &Task, undeclared: &Undeclared) {
+        let print = || {
+            let list = |heading: &str, paths: &[PathBuf]| {
+                if !paths.is_empty() {
+                    eprintln!("   {heading}:");
+                    for path in paths {
+                        eprintln!("     {}", path.display());
+                    }
+                }
+            };
+            eprintln!("⚠️  Task '{}' used files it doesn't declare", task.name);
+            list("Read, but not in `cache`", &undeclared.reads);
+            list("Written, but not in `outputs`", &undeclared.writes);
+            eprintln!("   Suggested additions:");
+            if !undeclared.input_patterns.is_empty() {
+                eprintln!("     cache: {:?}", undeclared.input_patterns);
+            }
+            if !undeclared.output_patterns.is_empty() {
+                eprintln!("     outputs: {:?}", undeclared.output_patterns);
+            }
         };
+        match self {
+            Reporter::Console => print(),
+            Reporter::Progress(progress) => progress.suspend(print),
+        }
+    }
 
-        // Set environment variables
-        for (key, value) in &task.env {
-            cmd.env(key, value);
+    fn waiting(self, task: &Task) {
+        match self {
+            Reporter::Console => println!(
+                "⏳ Task '{}' is being run by another rush, waiting for it",
+                task.name
+            ),
+            Reporter::Progress(progress) => {
+                progress.set_message(format!("⏳ {} (waiting for another rush)", task.name))
+            }
         }
+    }
 
-        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    fn skipped(self, task: &Task, reason: &str) {
+        match self {
+            Reporter::Console => println!("⏭️  Task '{}' skipped ({reason})", task.name),
+            Reporter::Progress(progress) => {
+                progress.set_message(format!("⏭️  {} skipped ({reason})", task.name))
+            }
+        }
+    }
 
-        let output = cmd.output().await?;
-        let elapsed = start_time.elapsed();
+    fn started(self, task: &Task) {
+        match self {
+            Reporter::Console => println!("🏃 Running task: {}", task.name),
+            Reporter::Progress(progress) => {
+                progress.set_message(format!("🏃 Running {}", task.name))
+            }
+        }
+    }
+
+    fn step(self, task: &Task, index: usize, total: usize, label: &str) {
+        match self {
+            Reporter::Console => println!("   ▶ [{}/{}] {}", index + 1, total, label),
+            Reporter::Progress(progress) => progress.set_message(format!(
+                "🏃 {} [{}/{}] {}",
+                task.name,
+                index + 1,
+                total,
+                label
+            )),
+        }
+    }
 
-        if output.status.success() {
-            progress.set_message(format!("✅ {} ({:.1}s)", task.name, elapsed.as_secs_f32()));
+    fn step_error_ignored(
+        self,
+        task: &Task,
+        index: usize,
+        total: usize,
+        error: &anyhow::Error,
+        redactor: &Redactor,
+    ) {
+        let message = format!(
+            "⚠️  {} step {}/{} failed, continuing: {}",
+            task.name,
+            index + 1,
+            total,
+            redactor.redact(&error.to_string())
+        );
+        match self {
+            Reporter::Console => println!("{message}"),
+            Reporter::Progress(progress) => progress.println(message),
+        }
+    }
 
-            // Cache the result if cache files are specified
-            if !task.cache_files.is_empty() {
-                let hash = cache.compute_task_hash(&task.name, &task.cache_files)?;
-                cache.mark_cached(&task.name, &hash)?;
+    fn succeeded(self, task: &Task, elapsed: Duration, output: &TaskOutput, redactor: &Redactor) {
+        match self {
+            Reporter::Console => {
+                println!("✅ Task '{}' completed successfully", task.name);
+                if !output.stdout.is_empty() {
+                    println!(
+                        "{}",
+                        redactor.redact(&String::from_utf8_lossy(&output.stdout))
+                    );
+                }
             }
-        } else {
-            progress.set_message(format!("❌ {} failed", task.name));
-            if !output.stderr.is_empty() {
-                eprintln!(
-                    "Error output for {}:\n{}",
-                    task.name,
-                    String::from_utf8_lossy(&output.stderr)
-                );
+            Reporter::Progress(progress) => {
+                progress.set_message(format!("✅ {} ({:.1}s)", task.name, elapsed.as_secs_f32()))
             }
-            anyhow::bail!(
-                "Task '{}' failed with exit code: {:?}",
-                task.name,
-                output.status.code()
-            );
         }
+    }
 
-        Ok(())
+    fn failed(self, task: &Task, output: &TaskOutput, redactor: &Redactor) {
+        match self {
+            Reporter::Console => {
+                println!("❌ Task '{}' failed", task.name);
+                if !output.stderr.is_empty() {
+                    eprintln!(
+                        "{}",
+                        redactor.redact(&String::from_utf8_lossy(&output.stderr))
+                    );
+                }
+            }
+            Reporter::Progress(progress) => {
+                progress.set_message(format!("❌ {} failed", task.name));
+                if !output.stderr.is_empty() {
+                    eprintln!(
+                        "Error output for {}:\n{}",
+                        task.name,
+                        redactor.redact(&String::from_utf8_lossy(&output.stderr))
+                    );
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,231 @@
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Files modified this recently are hashed but not recorded, since a second
+/// write within the filesystem's timestamp granularity would go unnoticed
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Persistent map from a file's size, modification time and inode to its
+/// content digest, so unchanged files don't have to be read again
+#[derive(Debug)]
+pub struct FileIndex {
+    path: PathBuf,
+    entries: HashMap<PathBuf, IndexEntry>,
+    updated: HashMap<PathBuf, IndexEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IndexEntry {
+    size: u64,
+    mtime_nanos: u128,
+    inode: u64,
+    digest: String,
+}
+
+impl IndexEntry {
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.len()
+            && self.mtime_nanos == mtime_nanos(metadata)
+            && self.inode == inode(metadata)
+    }
+}
+
+impl FileIndex {
+    /// Load the index, starting empty if it is missing or unreadable
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let entries = read_entries(&path);
+
+        Self {
+            path,
+            entries,
+            updated: HashMap::new(),
+        }
+    }
+
+    /// Content digests of existing files, in order. Files whose size,
+    /// modification time and inode match the index are not read at all; the
+    /// rest are hashed in parallel.
+    pub fn digests(&mut self, files: &[PathBuf]) -> Result<Vec<String>> {
+        let mut digests = vec![String::new(); files.len()];
+        let mut misses = Vec::new();
+
+        for (position, file) in files.iter().enumerate() {
+            let metadata =
+                fs::metadata(file).with_context(|| format!("Failed to read {}", file.display()))?;
+            match self.entries.get(file) {
+                Some(entry) if entry.matches(&metadata) => digests[position] = entry.digest.clone(),
+                _ => misses.push((position, metadata)),
+            }
+        }
+
+        let miss_files: Vec<PathBuf> = misses
+            .iter()
+            .map(|(position, _)| files[*position].clone())
+            .collect();
+        let hashed = hash_files(&miss_files)?;
+        let now = SystemTime::now();
+
+        for ((position, metadata), digest) in misses.into_iter().zip(hashed) {
+            let settled = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age >= RACY_WINDOW);
+            if settled {
+                let entry = IndexEntry {
+                    size: metadata.len(),
+                    mtime_nanos: mtime_nanos(&metadata),
+                    inode: inode(&metadata),
+                    digest: digest.clone(),
+                };
+                self.entries.insert(files[position].clone(), entry.clone());
+                self.updated.insert(files[position].clone(), entry);
+            }
+            digests[position] = digest;
+        }
+
+        Ok(digests)
+    }
+
+    /// Write new entries back, merged with whatever other rush processes
+    /// recorded since this index was loaded, replacing the file atomically
+    pub fn save(&self) -> Result<()> {
+        if self.updated.is_empty() {
+            return Ok(());
+        }
+
+        let mut entries = read_entries(&self.path);
+        entries.extend(self.updated.clone());
+
+        let mut content = String::new();
+        for (path, entry) in &entries {
+            content.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\n",
+                entry.size,
+                entry.mtime_nanos,
+                entry.inode,
+                entry.digest,
+                path.display()
+            ));
+        }
+
+        crate::backend::write_atomic(&self.path, content.as_bytes())
+    }
+}
+
+/// Hash files in parallel with streaming reads, returning digests in order
+pub fn hash_files(files: &[PathBuf]) -> Result<Vec<String>> {
+    let workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(files.len());
+    if workers <= 1 {
+        return files.iter().map(|file| hash_file(file)).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<String>>>> =
+        Mutex::new((0..files.len()).map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let position = next.fetch_add(1, Ordering::Relaxed);
+                let Some(file) = files.get(position) else {
+                    break;
+                };
+                let digest = hash_file(file);
+                results.lock().unwrap()[position] = Some(digest);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|digest| digest.expect("every file is hashed by a worker"))
+        .collect()
+}
+
+/// Content digest of one file, read in fixed-size chunks
+pub fn hash_file(file: &Path) -> Result<String> {
+    let mut reader =
+        File::open(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    copy_hashed(&mut reader, &mut io::sink())
+        .with_context(|| format!("Failed to read {}", file.display()))
+}
+
+/// Copy `reader` to `writer` in fixed-size chunks, returning the digest of
+/// what was copied
+pub fn copy_hashed(reader: &mut dyn Read, writer: &mut dyn Write) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn read_entries(path: &Path) -> HashMap<PathBuf, IndexEntry> {
+    fs::read_to_string(path)
+        .map(|content| content.lines().filter_map(parse_line).collect())
+        .unwrap_or_default()
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, IndexEntry)> {
+    let mut fields = line.splitn(5, '\t');
+    let size = fields.next()?.parse().ok()?;
+    let mtime_nanos = fields.next()?.parse().ok()?;
+    let inode = fields.next()?.parse().ok()?;
+    let digest = fields.next()?.to_string();
+    let path = PathBuf::from(fields.next()?);
+
+    Some((
+        path,
+        IndexEntry {
+            size,
+            mtime_nanos,
+            inode,
+            digest,
+        },
+    ))
+}
+
+fn mtime_nanos(metadata: &Metadata) -> u128 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
@@ -0,0 +1,80 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Lexically resolve `.` and `..`, without following symlinks
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Create a temporary file only the current user can access, named after the
+/// task so leftovers can be traced back. The file must not exist yet, so a
+/// link planted at the path in the shared temp directory can't redirect it.
+pub fn create_private_temp(
+    prefix: &str,
+    task_name: &str,
+    extension: &str,
+) -> Result<(PathBuf, File)> {
+    let path = temp_path(prefix, task_name, extension);
+    let mut options = File::options();
+    options.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options
+        .open(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok((path, file))
+}
+
+/// Create a temporary directory only the current user can access, the same
+/// way as `create_private_temp`
+pub fn create_private_temp_dir(prefix: &str, task_name: &str) -> Result<PathBuf> {
+    let path = temp_path(prefix, task_name, "");
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder
+        .create(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    Ok(path)
+}
+
+/// `rush-<prefix>-<task>-<pid>-<n>[.<extension>]` in the temp directory,
+/// unique within this process
+fn temp_path(prefix: &str, task_name: &str, extension: &str) -> PathBuf {
+    static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut name = format!(
+        "rush-{}-{}-{}-{}",
+        prefix,
+        task_name,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(extension);
+    }
+    let name: String = name
+        .chars()
+        .map(|c| if std::path::is_separator(c) { '_' } else { c })
+        .collect();
+    std::env::temp_dir().join(name)
+}
@@ -1,18 +1,133 @@
-use anyhow::{bail, Result};
-use std::collections::{HashMap, HashSet};
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::{Freshness, Limits, RushConfig, StepConfig};
+use crate::env::{self, EnvLayer};
+use crate::params::{self, TaskArgs};
+use crate::sandbox::Sandbox;
+use crate::secrets;
+use crate::shell::Shell;
+use crate::template::TemplateEngine;
 
 #[derive(Debug, Clone)]
 pub struct TaskGraph {
     pub tasks: HashMap<String, Task>,
     pub dependencies: HashMap<String, Vec<String>>,
+    /// Units of each named resource tasks share when running in parallel
+    pub resources: BTreeMap<String, u64>,
+    /// Directory of the root config, which `cache` and `outputs` patterns
+    /// are relative to
+    pub root_dir: PathBuf,
 }
 
 #[derive(Debug, Clone)]
 pub struct Task {
     pub name: String,
-    pub cmd: String,
+    /// Commands run in order; a task with a single `cmd` has one step
+    pub steps: Vec<Step>,
     pub env: HashMap<String, String>,
+    /// Layer each variable in `env` came from, for verbose output
+    pub env_sources: HashMap<String, String>,
+    pub env_layers: Vec<EnvLayer>,
+    /// Secret name patterns from the global and task `secrets` lists
+    pub secrets: Vec<String>,
+    /// Variable name patterns from the global and task `env_passthrough` lists
+    pub env_passthrough: Vec<String>,
+    /// Start commands with only `env` and passed-through variables
+    pub isolated: bool,
+    /// Confine commands to namespaces with the project read-only
+    pub sandbox: Option<Sandbox>,
+    pub limits: Limits,
+    /// Locked against other rush processes, even without `cache` inputs
+    pub exclusive: bool,
+    /// `--jobs` slots the task takes while running
+    pub weight: u64,
+    /// Units of named resources the task holds while running
+    pub resources: BTreeMap<String, u64>,
+    /// Working directory, already resolved against the task's `.rush` file
+    pub dir: Option<PathBuf>,
+    pub shell: Shell,
     pub cache_files: Vec<String>,
+    pub outputs: Vec<String>,
+    pub freshness: Freshness,
+    pub params: HashMap<String, crate::config::Param>,
+    pub when: Option<String>,
+    pub status: Vec<String>,
+    /// Set by `bind_args` when the `when` condition is false
+    pub skip_reason: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub kind: StepKind,
+    /// Keep going with the next step if this one fails
+    pub ignore_error: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum StepKind {
+    Command(String),
+    Script {
+        interpreter: Option<String>,
+        source: String,
+    },
+    /// Reference to another task by name, replaced by `Inline` in `bind_args`
+    Task(String),
+    /// Another task's steps, run with that task's shell, directory and environment
+    Inline(Box<Task>),
+}
+
+impl Step {
+    fn from_config(step: &StepConfig) -> Self {
+        match step {
+            StepConfig::Command(cmd) => Step {
+                kind: StepKind::Command(cmd.clone()),
+                ignore_error: false,
+            },
+            StepConfig::Detailed(detail) => {
+                let kind = match (&detail.cmd, &detail.task, &detail.script) {
+                    (Some(cmd), _, _) => StepKind::Command(cmd.clone()),
+                    (None, Some(task), _) => StepKind::Task(task.clone()),
+                    (None, None, script) => StepKind::Script {
+                        interpreter: detail.interpreter.clone(),
+                        source: script.clone().unwrap_or_default(),
+                    },
+                };
+                Step {
+                    kind,
+                    ignore_error: detail.ignore_error,
+                }
+            }
+        }
+    }
+
+    /// Short description of the step for progress output
+    pub fn label(&self) -> String {
+        match &self.kind {
+            StepKind::Command(cmd) => cmd.lines().next().unwrap_or_default().to_string(),
+            StepKind::Script { interpreter, .. } => {
+                format!("{} script", interpreter.as_deref().unwrap_or("shell"))
+            }
+            StepKind::Task(name) => format!("task {name}"),
+            StepKind::Inline(task) => format!("task {}", task.name),
+        }
+    }
+}
+
+impl Task {
+    pub fn is_secret(&self, key: &str) -> bool {
+        secrets::is_secret_name(key, &self.secrets)
+    }
+
+    /// Variables the task's commands see that the cache key covers: those
+    /// passed through from rush's environment and those the task declares
+    pub fn cache_env(&self) -> BTreeMap<String, String> {
+        let mut vars = env::passthrough_vars(&self.env_passthrough);
+        vars.extend(self.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        vars
+    }
 }
 
 impl TaskGraph {
@@ -20,6 +135,8 @@ impl TaskGraph {
         Self {
             tasks: HashMap::new(),
             dependencies: HashMap::new(),
+            resources: BTreeMap::new(),
+            root_dir: PathBuf::from("."),
         }
     }
 
@@ -32,6 +149,174 @@ impl TaskGraph {
         self.dependencies.entry(task).or_default().push(dependency);
     }
 
+    /// Validate parameters and render the templates in each task that will run.
+    ///
+    /// The target task receives the command-line arguments and passthrough
+    /// arguments; its dependencies use their parameter defaults.
+    pub fn bind_args(
+        &mut self,
+        target: &str,
+        args: &TaskArgs,
+        templates: &TemplateEngine,
+    ) -> Result<()> {
+        if !self.tasks.contains_key(target) {
+            // Reported with the list of available tasks by the executor
+            return Ok(());
+        }
+
+        let no_args = TaskArgs::default();
+        let mut to_bind = self.topological_sort(target)?;
+
+        // Tasks referenced from steps are rendered too, so they can be inlined below
+        let mut index = 0;
+        while index < to_bind.len() {
+            for step in &self.tasks[&to_bind[index]].steps {
+                if let StepKind::Task(referenced) = &step.kind {
+                    if !self.tasks.contains_key(referenced) {
+                        bail!(
+                            "Task '{}' has a step referencing unknown task '{}'",
+                            to_bind[index],
+                            referenced
+                        );
+                    }
+                    if !to_bind.contains(referenced) {
+                        to_bind.push(referenced.clone());
+                    }
+                }
+            }
+            index += 1;
+        }
+
+        for name in &to_bind {
+            let task_args = if name == target { args } else { &no_args };
+            if let Some(task) = self.tasks.get_mut(name) {
+                let values = params::resolve(name, &task.params, task_args)?;
+                let render = |template: &str| {
+                    templates
+                        .render(template, &values)
+                        .with_context(|| format!("Failed to render templates in task '{name}'"))
+                };
+                let shell = task.shell;
+                let script_shell = match shell {
+                    Shell::None => Shell::platform_default(),
+                    shell => shell,
+                };
+                let render_command = |template: &str, shell: Shell| {
+                    templates
+                        .render_command(template, &values, shell)
+                        .with_context(|| format!("Failed to render templates in task '{name}'"))
+                };
+
+                if let Some(when) = &task.when {
+                    let holds = templates
+                        .evaluate(when, &values)
+                        .with_context(|| format!("Invalid when condition in task '{name}'"))?;
+                    task.skip_reason = (!holds).then(|| format!("when: {when}"));
+                }
+                if task.skip_reason.is_some() {
+                    // A skipped task's steps, env and dir may only make sense
+                    // when its condition holds, so none of them are evaluated
+                    continue;
+                }
+
+                for step in task.steps.iter_mut() {
+                    match &mut step.kind {
+                        StepKind::Command(cmd) => *cmd = render_command(cmd, shell)?,
+                        // Other interpreters don't read shell quoting, and
+                        // scripts of `shell: none` tasks run with the default
+                        StepKind::Script {
+                            interpreter: None,
+                            source,
+                        } => *source = render_command(source, script_shell)?,
+                        StepKind::Script { source, .. } => *source = render(source)?,
+                        StepKind::Task(_) | StepKind::Inline(_) => {}
+                    }
+                }
+                if !task_args.passthrough.is_empty() {
+                    let last_command = task
+                        .steps
+                        .iter_mut()
+                        .rev()
+                        .find_map(|step| match &mut step.kind {
+                            StepKind::Command(cmd) => Some(cmd),
+                            _ => None,
+                        })
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "Task '{name}' has no command step to pass arguments to"
+                            )
+                        })?;
+                    *last_command =
+                        params::append_passthrough(last_command, &task_args.passthrough, shell)?;
+                }
+
+                (task.env, task.env_sources) = env::resolve_layers(&task.env_layers, render)
+                    .with_context(|| format!("Failed to load environment for task '{name}'"))?;
+                for file in task.cache_files.iter_mut() {
+                    *file = render(file)?;
+                }
+                for output in task.outputs.iter_mut() {
+                    *output = render(output)?;
+                }
+                for check in task.status.iter_mut() {
+                    *check = render_command(check, shell)?;
+                }
+                if let Some(dir) = &task.dir {
+                    let dir = PathBuf::from(render(&dir.to_string_lossy())?);
+                    if !dir.is_dir() {
+                        bail!(
+                            "Working directory {} for task '{}' does not exist",
+                            dir.display(),
+                            name
+                        );
+                    }
+                    task.dir = Some(dir);
+                }
+            }
+        }
+
+        for name in &to_bind {
+            let steps = self.inline_steps(name, &mut vec![name.clone()])?;
+            if let Some(task) = self.tasks.get_mut(name) {
+                task.steps = steps;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace task references in a task's steps with the referenced tasks
+    fn inline_steps(&self, name: &str, stack: &mut Vec<String>) -> Result<Vec<Step>> {
+        let mut steps = Vec::new();
+
+        for step in &self.tasks[name].steps {
+            let StepKind::Task(referenced) = &step.kind else {
+                steps.push(step.clone());
+                continue;
+            };
+
+            if stack.contains(referenced) {
+                bail!(
+                    "Circular step reference: {} -> {}",
+                    stack.join(" -> "),
+                    referenced
+                );
+            }
+
+            stack.push(referenced.clone());
+            let mut inlined = self.tasks[referenced].clone();
+            inlined.steps = self.inline_steps(referenced, stack)?;
+            stack.pop();
+
+            steps.push(Step {
+                kind: StepKind::Inline(Box::new(inlined)),
+                ignore_error: step.ignore_error,
+            });
+        }
+
+        Ok(steps)
+    }
+
     pub fn topological_sort(&self, start_task: &str) -> Result<Vec<String>> {
         // Pre-allocate with estimated capacity for better performance
         let estimated_size = self.estimate_task_count(start_task);
@@ -44,6 +329,50 @@ impl TaskGraph {
         Ok(stack)
     }
 
+    /// Longest estimated time from starting each task in `order` to the end
+    /// of the run, following the tasks in `order` that depend on it. Tasks
+    /// without a recorded duration count as the median of those with one.
+    pub fn critical_paths(
+        &self,
+        order: &[String],
+        durations: &HashMap<String, Duration>,
+    ) -> HashMap<String, Duration> {
+        let mut known: Vec<Duration> = order
+            .iter()
+            .filter_map(|name| durations.get(name).copied())
+            .collect();
+        known.sort();
+        let typical = known
+            .get(known.len() / 2)
+            .copied()
+            .unwrap_or(Duration::from_secs(1));
+
+        let mut dependents: HashMap<&str, Vec<&str>> = HashMap::new();
+        for name in order {
+            for dep in self.dependencies.get(name).into_iter().flatten() {
+                dependents.entry(dep).or_default().push(name);
+            }
+        }
+
+        // Dependents come later in a topological order, so walking it
+        // backwards sees their paths first
+        let mut paths: HashMap<String, Duration> = HashMap::with_capacity(order.len());
+        for name in order.iter().rev() {
+            let longest_after = dependents
+                .get(name.as_str())
+                .into_iter()
+                .flatten()
+                .filter_map(|dependent| paths.get(*dependent))
+                .max()
+                .copied()
+                .unwrap_or_default();
+            let own = durations.get(name).copied().unwrap_or(typical);
+            paths.insert(name.clone(), own + longest_after);
+        }
+
+        paths
+    }
+
     fn estimate_task_count(&self, start_task: &str) -> usize {
         // Simple heuristic: estimate based on dependency depth
         let mut count = 1;
@@ -100,16 +429,87 @@ impl TaskGraph {
     }
 }
 
-impl From<&crate::config::RushConfig> for TaskGraph {
-    fn from(config: &crate::config::RushConfig) -> Self {
+impl From<&RushConfig> for TaskGraph {
+    fn from(config: &RushConfig) -> Self {
         let mut graph = TaskGraph::new();
+        graph.root_dir = Path::new(".").join(RushConfig::base_dir(&config.source));
+        graph.resources = config
+            .resources
+            .iter()
+            .map(|(name, units)| (name.clone(), *units))
+            .collect();
+
+        let root_dir = RushConfig::base_dir(&config.source);
+        let mut global_layers: Vec<EnvLayer> = config
+            .env_file
+            .iter()
+            .map(|file| EnvLayer::File(root_dir.join(file)))
+            .collect();
+        global_layers.push(EnvLayer::Inline {
+            label: "global env",
+            vars: config.env.clone(),
+        });
 
         for (name, task_config) in &config.tasks {
+            let task_dir = RushConfig::base_dir(&task_config.source);
+            let mut env_layers = global_layers.clone();
+            env_layers.extend(
+                task_config
+                    .env_file
+                    .iter()
+                    .map(|file| EnvLayer::File(task_dir.join(file))),
+            );
+            env_layers.push(EnvLayer::Inline {
+                label: "task env",
+                vars: task_config.env.clone(),
+            });
+
             let task = Task {
                 name: name.clone(),
-                cmd: task_config.cmd.clone(),
+                steps: if task_config.cmds.is_empty() {
+                    vec![Step::from_config(&StepConfig::Command(
+                        task_config.cmd.clone(),
+                    ))]
+                } else {
+                    task_config.cmds.iter().map(Step::from_config).collect()
+                },
                 env: task_config.env.clone(),
+                env_sources: HashMap::new(),
+                env_layers,
+                secrets: config
+                    .secrets
+                    .iter()
+                    .chain(&task_config.secrets)
+                    .cloned()
+                    .collect(),
+                env_passthrough: config
+                    .env_passthrough
+                    .iter()
+                    .chain(&task_config.env_passthrough)
+                    .cloned()
+                    .collect(),
+                isolated: task_config.isolated.unwrap_or(config.isolated),
+                sandbox: task_config.sandbox.as_ref().and_then(|s| s.settings()),
+                limits: task_config.limits.clone(),
+                exclusive: task_config.exclusive,
+                weight: task_config.weight.unwrap_or(1),
+                resources: task_config
+                    .resources
+                    .iter()
+                    .map(|(name, amount)| (name.clone(), *amount))
+                    .collect(),
+                dir: task_config.dir.as_ref().map(|dir| task_dir.join(dir)),
+                shell: task_config
+                    .shell
+                    .or(config.shell)
+                    .unwrap_or_else(Shell::platform_default),
                 cache_files: task_config.cache.clone(),
+                outputs: task_config.outputs.clone(),
+                freshness: task_config.freshness,
+                params: task_config.params.clone(),
+                when: task_config.when.clone(),
+                status: task_config.status.clone(),
+                skip_reason: None,
             };
 
             graph.add_task(name.clone(), task);
@@ -122,3 +522,53 @@ impl From<&crate::config::RushConfig> for TaskGraph {
         graph
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn critical_paths_follow_the_longest_chain() {
+        // fetch -> build -> test, fetch -> lint
+        let mut graph = TaskGraph::new();
+        for (task, deps) in [
+            ("fetch", vec![]),
+            ("build", vec!["fetch"]),
+            ("test", vec!["build"]),
+            ("lint", vec!["fetch"]),
+        ] {
+            graph.dependencies.insert(
+                task.to_string(),
+                deps.into_iter().map(String::from).collect(),
+            );
+        }
+        let order: Vec<String> = ["fetch", "build", "lint", "test"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let durations = HashMap::from([
+            ("fetch".to_string(), secs(2)),
+            ("build".to_string(), secs(10)),
+            ("lint".to_string(), secs(1)),
+        ]);
+
+        let paths = graph.critical_paths(&order, &durations);
+        // `test` has no duration and counts as the median, 2s
+        assert_eq!(paths["test"], secs(2));
+        assert_eq!(paths["build"], secs(12));
+        assert_eq!(paths["lint"], secs(1));
+        assert_eq!(paths["fetch"], secs(14));
+    }
+
+    #[test]
+    fn critical_paths_without_history() {
+        let mut graph = TaskGraph::new();
+        graph.dependencies.insert("a".to_string(), vec![]);
+        let paths = graph.critical_paths(&["a".to_string()], &HashMap::new());
+        assert_eq!(paths["a"], secs(1));
+    }
+}
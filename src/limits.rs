@@ -0,0 +1,368 @@
+use anyhow::{bail, Result};
+use std::process::Output;
+use tokio::process::Command;
+
+use crate::cachecmd::{format_size, parse_size};
+use crate::config::Limits;
+
+/// Messages programs print when an allocation fails
+const OUT_OF_MEMORY: &[&str] = &[
+    "cannot allocate memory",
+    "out of memory",
+    "memory allocation of",
+    "bad_alloc",
+    "memoryerror",
+];
+
+/// What `strerror(EMFILE)` prints
+const TOO_MANY_FILES: &str = "too many open files";
+
+/// The limits applied to one command, checked after it exits
+pub struct Enforcement {
+    memory: Option<u64>,
+    nofile: Option<u64>,
+    #[cfg(target_os = "linux")]
+    cgroup: Option<cgroup::Cgroup>,
+}
+
+impl Enforcement {
+    /// How a failed command ran into its limits, if it did
+    pub fn exceeded(&self, output: &Output) -> Option<String> {
+        if output.status.success() {
+            return None;
+        }
+
+        #[cfg(target_os = "linux")]
+        if let (Some(cgroup), Some(memory)) = (&self.cgroup, self.memory) {
+            if cgroup.oom_killed() {
+                return Some(format!(
+                    "was killed for exceeding its memory limit of {}",
+                    format_size(memory)
+                ));
+            }
+        }
+        #[cfg(target_os = "linux")]
+        let in_cgroup = self.cgroup.is_some();
+        #[cfg(not(target_os = "linux"))]
+        let in_cgroup = false;
+
+        let printed = format!(
+            "{}\n{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .to_lowercase();
+
+        // A cgroup reports its OOM kills, so what the command printed only
+        // tells under RLIMIT_DATA, where allocations fail instead
+        if let Some(memory) = self.memory.filter(|_| !in_cgroup) {
+            if OUT_OF_MEMORY
+                .iter()
+                .any(|message| printed.contains(message))
+            {
+                return Some(format!(
+                    "ran out of memory under its limit of {}",
+                    format_size(memory)
+                ));
+            }
+        }
+        if let Some(nofile) = self.nofile {
+            if printed.contains(TOO_MANY_FILES) {
+                return Some(format!(
+                    "ran out of file descriptors under its limit of {nofile} open files"
+                ));
+            }
+        }
+
+        None
+    }
+}
+
+/// Apply a task's limits to a command: memory and CPU shares through a
+/// cgroup v2 child when one can be created, or `RLIMIT_DATA` and a lower
+/// priority otherwise, and open files through `RLIMIT_NOFILE`
+#[cfg(unix)]
+pub fn apply(cmd: &mut Command, task_name: &str, limits: &Limits) -> Result<Enforcement> {
+    let memory = limits.memory.as_deref().map(parse_size).transpose()?;
+
+    if let Some(nofile) = limits.nofile {
+        let hard = nofile_hard_limit()?;
+        if hard != libc::RLIM_INFINITY && nofile > hard as u64 {
+            bail!(
+                "nofile limit {} of task '{}' is above the system's hard limit of {}",
+                nofile,
+                task_name,
+                hard
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    let cgroup = if memory.is_some() || limits.cpu_shares.is_some() {
+        match cgroup::Cgroup::create(task_name, memory, limits.cpu_shares) {
+            Ok(cgroup) => Some(cgroup),
+            Err(e) => {
+                warn_no_cgroup(&e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(target_os = "linux")]
+    let procs = cgroup.as_ref().map(|cgroup| cgroup.procs()).transpose()?;
+    #[cfg(target_os = "linux")]
+    let in_cgroup = cgroup.is_some();
+    #[cfg(not(target_os = "linux"))]
+    let in_cgroup = {
+        if memory.is_some() || limits.cpu_shares.is_some() {
+            warn_no_cgroup(&anyhow::anyhow!("cgroups are Linux-only"));
+        }
+        false
+    };
+
+    // The child runs this between fork and exec, so everything is
+    // computed here
+    let data = memory.filter(|_| !in_cgroup).map(|bytes| libc::rlimit {
+        rlim_cur: bytes as libc::rlim_t,
+        rlim_max: bytes as libc::rlim_t,
+    });
+    let nofile = limits.nofile.map(|files| libc::rlimit {
+        rlim_cur: files as libc::rlim_t,
+        rlim_max: files as libc::rlim_t,
+    });
+    let priority = match limits.cpu_shares.filter(|_| !in_cgroup) {
+        Some(shares) => {
+            let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+            Some((current + niceness(shares)).min(19))
+        }
+        None => None,
+    };
+
+    let setup = move || -> std::io::Result<()> {
+        let check = |result: libc::c_int| {
+            if result == -1 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+
+        #[cfg(target_os = "linux")]
+        if let Some(procs) = &procs {
+            use std::io::Write;
+            // "0" moves the writing process
+            (&*procs).write_all(b"0")?;
+        }
+        if let Some(data) = &data {
+            unsafe { check(libc::setrlimit(libc::RLIMIT_DATA, data))? };
+        }
+        if let Some(nofile) = &nofile {
+            unsafe { check(libc::setrlimit(libc::RLIMIT_NOFILE, nofile))? };
+        }
+        if let Some(priority) = priority {
+            unsafe { check(libc::setpriority(libc::PRIO_PROCESS, 0, priority))? };
+        }
+        Ok(())
+    };
+    unsafe {
+        cmd.pre_exec(setup);
+    }
+
+    Ok(Enforcement {
+        memory,
+        nofile: limits.nofile,
+        #[cfg(target_os = "linux")]
+        cgroup,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn apply(_cmd: &mut Command, task_name: &str, _limits: &Limits) -> Result<Enforcement> {
+    bail!(
+        "Task '{}' sets limits, which are not supported on this platform",
+        task_name
+    );
+}
+
+#[cfg(unix)]
+fn nofile_hard_limit() -> Result<libc::rlim_t> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(limit.rlim_max)
+}
+
+/// Nice increment giving roughly `shares / 1024` of the CPU time of a
+/// default process, since each nice level is worth about 25%
+fn niceness(shares: u64) -> i32 {
+    if shares >= 1024 {
+        return 0;
+    }
+    let levels = (1024.0 / shares as f64).ln() / 1.25f64.ln();
+    (levels.round() as i32).min(19)
+}
+
+/// Say once per run that limits fall back to rlimits
+fn warn_no_cgroup(reason: &anyhow::Error) {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    static WARNED: AtomicBool = AtomicBool::new(false);
+
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        eprintln!(
+            "⚠️  Can't create a cgroup for task limits ({reason}); limiting memory with RLIMIT_DATA and cpu_shares with a lower priority instead"
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup {
+    use anyhow::{bail, Context, Result};
+    use std::fs::{self, File, OpenOptions};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// A cgroup v2 group holding one command, removed once dropped
+    pub struct Cgroup {
+        path: PathBuf,
+    }
+
+    impl Cgroup {
+        /// Create a group next to rush's own, which is the closest place an
+        /// unprivileged process can usually create one: rush's group holds
+        /// processes, so it can't hand controllers down to children
+        pub fn create(
+            task_name: &str,
+            memory: Option<u64>,
+            cpu_shares: Option<u64>,
+        ) -> Result<Self> {
+            static CGROUP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+            let mount = mount_point().context("no cgroup v2 hierarchy is mounted")?;
+            let own = fs::read_to_string("/proc/self/cgroup")?
+                .lines()
+                .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+                .context("rush isn't in a cgroup v2 group")?;
+            let own = match own.trim_start_matches('/') {
+                "" => mount.clone(),
+                relative => mount.join(relative),
+            };
+            if own
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("rush-"))
+            {
+                // A sibling would escape the limits of the task running us
+                bail!("rush is already running inside a limited task");
+            }
+            let base = if own == mount {
+                own
+            } else {
+                own.parent().map(PathBuf::from).unwrap_or(own)
+            };
+
+            let controllers = fs::read_to_string(base.join("cgroup.subtree_control"))?;
+            let needed = [("memory", memory.is_some()), ("cpu", cpu_shares.is_some())];
+            for (controller, used) in needed {
+                if used && !controllers.split_whitespace().any(|c| c == controller) {
+                    bail!(
+                        "the {controller} controller isn't enabled in {}",
+                        base.display()
+                    );
+                }
+            }
+
+            let name: String = format!(
+                "rush-{}-{}-{}",
+                task_name,
+                std::process::id(),
+                CGROUP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            )
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+            let path = base.join(name);
+            fs::create_dir(&path).with_context(|| format!("can't create {}", path.display()))?;
+            let cgroup = Cgroup { path };
+
+            if let Some(bytes) = memory {
+                cgroup.write("memory.max", &bytes.to_string())?;
+                // Swapping out instead would hide the overrun behind slowness
+                let _ = cgroup.write("memory.swap.max", "0");
+            }
+            if let Some(shares) = cpu_shares {
+                // The kernel's conversion from cgroup v1 shares to v2 weights
+                let weight = 1 + (shares - 2) * 9999 / 262142;
+                cgroup.write("cpu.weight", &weight.to_string())?;
+            }
+
+            Ok(cgroup)
+        }
+
+        fn write(&self, file: &str, value: &str) -> Result<()> {
+            fs::write(self.path.join(file), value)
+                .with_context(|| format!("can't set {file} of {}", self.path.display()))
+        }
+
+        /// `cgroup.procs` opened for writing, for the child to move itself in
+        pub fn procs(&self) -> Result<File> {
+            let path = self.path.join("cgroup.procs");
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))
+        }
+
+        /// Whether the kernel killed a process for exceeding `memory.max`
+        pub fn oom_killed(&self) -> bool {
+            fs::read_to_string(self.path.join("memory.events"))
+                .map(|events| {
+                    events.lines().any(|line| {
+                        line.strip_prefix("oom_kill ")
+                            .and_then(|count| count.trim().parse::<u64>().ok())
+                            .is_some_and(|count| count > 0)
+                    })
+                })
+                .unwrap_or(false)
+        }
+    }
+
+    impl Drop for Cgroup {
+        fn drop(&mut self) {
+            // Stop whatever the command left running, then wait for the
+            // group to empty so it can be removed, off the async workers
+            tokio::task::block_in_place(|| {
+                let _ = fs::write(self.path.join("cgroup.kill"), "1");
+                for _ in 0..50 {
+                    if fs::remove_dir(&self.path).is_ok() {
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            });
+        }
+    }
+
+    /// Where the cgroup v2 hierarchy is mounted, which is `/sys/fs/cgroup`
+    /// on most systems and `/sys/fs/cgroup/unified` on hybrid ones
+    fn mount_point() -> Option<PathBuf> {
+        let mounts = fs::read_to_string("/proc/self/mountinfo").ok()?;
+        mounts.lines().find_map(|line| {
+            let (fields, filesystem) = line.split_once(" - ")?;
+            if filesystem.split_whitespace().next()? != "cgroup2" {
+                return None;
+            }
+            fields.split_whitespace().nth(4).map(PathBuf::from)
+        })
+    }
+}
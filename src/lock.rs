@@ -0,0 +1,85 @@
+use anyhow::{bail, Context, Result};
+use fs4::fs_std::FileExt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+/// Advisory lock on a file, released when dropped (or when the process dies,
+/// so a crashed run never leaves a stale lock behind)
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Take the lock, returning `None` if another process holds it
+    pub fn try_acquire(path: &Path) -> Result<Option<Self>> {
+        let file = open(path)?;
+        match file.try_lock_exclusive() {
+            Ok(true) => Ok(Some(Self { file })),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to lock {}", path.display())),
+        }
+    }
+
+    /// Take the lock, waiting for any other holder to release it
+    pub fn acquire(path: &Path) -> Result<Self> {
+        let file = open(path)?;
+        file.lock_exclusive()
+            .with_context(|| format!("Failed to lock {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    /// Take the lock shared with other shared holders, waiting for an
+    /// exclusive holder to release it
+    pub fn acquire_shared(path: &Path) -> Result<Self> {
+        let file = open(path)?;
+        FileExt::lock_shared(&file)
+            .with_context(|| format!("Failed to lock {}", path.display()))?;
+        Ok(Self { file })
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    File::options()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))
+}
+
+/// Lock held for a whole rush invocation when the config sets `run_lock`
+#[derive(Debug)]
+pub struct RunLock {
+    _lock: FileLock,
+}
+
+impl RunLock {
+    /// Take the run lock for `task_name`, failing with the task and process
+    /// that hold it if another rush is already running here
+    pub fn acquire(path: &Path, task_name: &str) -> Result<Self> {
+        let Some(mut lock) = FileLock::try_acquire(path)? else {
+            let holder = fs::read_to_string(path).unwrap_or_default();
+            match holder.trim().split_once('\t') {
+                Some((pid, task)) => bail!(
+                    "Another rush is running task '{}' here (pid {}); wait for it to finish",
+                    task,
+                    pid
+                ),
+                None => bail!("Another rush is running here; wait for it to finish"),
+            }
+        };
+
+        // Record the holder for the message above, through the locked handle
+        lock.file.set_len(0)?;
+        write!(lock.file, "{}\t{}", std::process::id(), task_name)?;
+        lock.file.flush()?;
+
+        Ok(Self { _lock: lock })
+    }
+}
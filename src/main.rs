@@ -1,25 +1,70 @@
+mod backend;
+mod blobstore;
 mod cache;
+mod cachecmd;
 mod config;
+mod env;
 mod executor;
+mod fileindex;
+mod fsutil;
 mod graph;
+mod limits;
+mod lock;
+mod manifest;
+mod params;
+mod resources;
+mod sandbox;
+mod secrets;
+mod server;
+mod shell;
+mod template;
+mod trace;
 
 use anyhow::Result;
+use cache::TaskCache;
 use clap::{Arg, Command};
 use config::RushConfig;
-use executor::TaskExecutor;
+use executor::{RunOptions, TaskExecutor};
 use graph::TaskGraph;
+use params::TaskArgs;
+use secrets::Redactor;
+use template::TemplateEngine;
+use trace::StrictInputs;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    let mut redactor = Redactor::from_process_env(&[]);
+
+    if let Err(e) = run(&mut redactor).await {
+        eprintln!("Error: {}", redactor.redact(&format!("{e:?}")));
+        std::process::exit(1);
+    }
+}
+
+async fn run(redactor: &mut Redactor) -> Result<()> {
     let matches = Command::new("rush")
         .version(env!("CARGO_PKG_VERSION"))
         .about("A modern task runner with parallel execution and intelligent caching")
+        .args_conflicts_with_subcommands(true)
         .arg(
             Arg::new("task")
                 .help("Task to run")
                 .required(false)
                 .index(1),
         )
+        .arg(
+            Arg::new("args")
+                .help("Task parameters as key=value")
+                .num_args(0..)
+                .index(2),
+        )
+        .arg(
+            Arg::new("passthrough")
+                .help("Arguments appended to the task's command")
+                .num_args(0..)
+                .index(3)
+                .last(true),
+        )
         .arg(
             Arg::new("parallel")
                 .short('j')
@@ -27,6 +72,13 @@ async fn main() -> Result<()> {
                 .help("Run tasks in parallel where possible")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .value_name("N")
+                .help("Job slots for parallel tasks, each taking its weight (default: CPU count; implies -j)")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
         .arg(
             Arg::new("list")
                 .short('l')
@@ -48,12 +100,94 @@ async fn main() -> Result<()> {
                 .help("Enable verbose output and debugging info")
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("no-replay")
+                .long("no-replay")
+                .help("Don't print the stored output of tasks restored from the cache")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cache-read-only")
+                .long("cache-read-only")
+                .help("Use shared cache backends without uploading to them (or set RUSH_CACHE_READ_ONLY=1)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strict-inputs")
+                .long("strict-inputs")
+                .value_name("MODE")
+                .help(
+                    "Trace file accesses and warn or fail on undeclared inputs and outputs (Linux)",
+                )
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("warn")
+                .value_parser(["warn", "fail"]),
+        )
+        .subcommand(cachecmd::command())
+        .subcommand(trace::command())
         .get_matches();
 
+    match matches.subcommand() {
+        Some(("cache", cache_matches)) => return cachecmd::run(cache_matches).await,
+        Some((trace::SUBCOMMAND, trace_matches)) => return trace::run(trace_matches),
+        _ => {}
+    }
+
     let config = RushConfig::find_config()?;
-    let graph = TaskGraph::from(&config);
+    *redactor = Redactor::from_process_env(&config.secrets);
+    let mut graph = TaskGraph::from(&config);
     let verbose = matches.get_flag("verbose");
-    let executor = TaskExecutor::new(graph, verbose);
+
+    if let Some(task_name) = matches.get_one::<String>("task") {
+        let args: Vec<String> = matches
+            .get_many::<String>("args")
+            .unwrap_or_default()
+            .cloned()
+            .collect();
+        let passthrough: Vec<String> = matches
+            .get_many::<String>("passthrough")
+            .unwrap_or_default()
+            .cloned()
+            .collect();
+        let task_args = TaskArgs::parse(&args, &passthrough)?;
+        graph.bind_args(task_name, &task_args, &TemplateEngine::new(&config))?;
+    }
+
+    *redactor = Redactor::from_graph(&graph, &config.secrets);
+    // For CI jobs that shouldn't be trusted to write to the shared cache
+    let cache_read_only = matches.get_flag("cache-read-only")
+        || std::env::var("RUSH_CACHE_READ_ONLY")
+            .is_ok_and(|value| !matches!(value.as_str(), "" | "0"));
+    let backends = config
+        .cache
+        .backends
+        .iter()
+        .map(|backend| backend.build(RushConfig::base_dir(&config.source), cache_read_only))
+        .collect::<Result<Vec<_>>>()?;
+    let cache = TaskCache::new(&config.cache, backends);
+    let _run_lock = match matches.get_one::<String>("task") {
+        Some(task_name) if config.run_lock && !matches.get_flag("list") => {
+            Some(cache.lock_run(task_name)?)
+        }
+        _ => None,
+    };
+    let executor = TaskExecutor::new(
+        graph,
+        cache,
+        verbose,
+        RunOptions {
+            jobs: matches.get_one::<u64>("jobs").copied().unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |cpus| cpus.get() as u64)
+            }),
+            replay: !matches.get_flag("no-replay"),
+            strict_inputs: matches
+                .get_one::<String>("strict-inputs")
+                .map(|mode| StrictInputs::parse(mode))
+                .transpose()?,
+        },
+        redactor.clone(),
+    );
 
     // Handle --list flag
     if matches.get_flag("list") {
@@ -66,17 +200,21 @@ async fn main() -> Result<()> {
                 Some(desc) => {
                     println!("  📦 {name}");
                     println!("     {desc}");
-                    println!("     Command: {}", task.cmd);
+                    println!("     Command: {}", task.command_summary());
                     if !task.deps.is_empty() {
                         println!("     Dependencies: {}", task.deps.join(", "));
                     }
+                    print_params(task);
+                    println!("     Source: {}", task.source.display());
                     println!();
                 }
                 None => {
-                    println!("  📦 {}: {}", name, task.cmd);
+                    println!("  📦 {}: {}", name, task.command_summary());
                     if !task.deps.is_empty() {
                         println!("     Dependencies: {}", task.deps.join(", "));
                     }
+                    print_params(task);
+                    println!("     Source: {}", task.source.display());
                     println!();
                 }
             }
@@ -85,7 +223,7 @@ async fn main() -> Result<()> {
     }
 
     if let Some(task_name) = matches.get_one::<String>("task") {
-        let parallel = matches.get_flag("parallel");
+        let parallel = matches.get_flag("parallel") || matches.contains_id("jobs");
         let watch = matches.get_flag("watch");
 
         if watch {
@@ -102,8 +240,8 @@ async fn main() -> Result<()> {
         println!("Available tasks:");
         for (name, task) in &config.tasks {
             match &task.description {
-                Some(desc) => println!("  {}: {} ({})", name, desc, task.cmd),
-                None => println!("  {}: {}", name, task.cmd),
+                Some(desc) => println!("  {}: {} ({})", name, desc, task.command_summary()),
+                None => println!("  {}: {}", name, task.command_summary()),
             }
         }
         println!("\nUse 'rush --list' for detailed task information");
@@ -111,3 +249,25 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn print_params(task: &config::Task) {
+    let mut params: Vec<_> = task.params.iter().collect();
+    params.sort_by_key(|(name, _)| *name);
+
+    for (name, param) in params {
+        let mut details = vec![param.param_type.to_string()];
+        match &param.default {
+            Some(default) => details.push(format!("default: {default}")),
+            None => details.push("required".to_string()),
+        }
+        if !param.allowed.is_empty() {
+            let allowed: Vec<_> = param.allowed.iter().map(|a| a.to_string()).collect();
+            details.push(format!("one of: {}", allowed.join(", ")));
+        }
+
+        match &param.description {
+            Some(desc) => println!("     Param {name} ({}): {desc}", details.join(", ")),
+            None => println!("     Param {name} ({})", details.join(", ")),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Stands in for the digest of an input that doesn't exist
+pub const MISSING_INPUT: &str = "-";
+
+/// Contents of a cache marker, locally and in shared backends:
+///
+/// ```text
+/// task     build
+/// created  1700000000
+/// duration 1520
+/// input    <sha256>  src/main.rs
+/// env      <sha256>  RUSTFLAGS
+/// output   <sha256>  755  dist/app
+/// stdout   <sha256>
+/// stderr   <sha256>
+/// artifact <sha256>
+/// checksum <sha256 of the lines above>
+/// ```
+///
+/// Fields are separated by tabs. The trailing checksum catches truncated and
+/// edited markers, and the artifact digest catches a damaged output archive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub task: String,
+    pub created: Option<SystemTime>,
+    /// How long the run took, stored in milliseconds
+    pub duration: Option<Duration>,
+    /// Each input file and its content digest, `None` if it doesn't exist
+    pub inputs: Vec<(PathBuf, Option<String>)>,
+    /// Each variable in the cache key and the digest of its value
+    pub env: Vec<(String, String)>,
+    /// Output files kept in the local blob store
+    pub outputs: Vec<OutputFile>,
+    /// Blobs holding the run's captured stdout and stderr, if it printed any
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    /// Digest of the archive of the task's outputs, if they were stored
+    pub artifact: Option<String>,
+}
+
+/// An output file recorded in a cache entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    pub digest: String,
+    /// Unix permission bits
+    pub mode: u32,
+}
+
+impl Manifest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = format!("task\t{}\n", self.task);
+        if let Some(created) = self.created {
+            body.push_str(&format!(
+                "created\t{}\n",
+                created
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            ));
+        }
+        if let Some(duration) = self.duration {
+            body.push_str(&format!("duration\t{}\n", duration.as_millis()));
+        }
+        for (path, digest) in &self.inputs {
+            body.push_str(&format!(
+                "input\t{}\t{}\n",
+                digest.as_deref().unwrap_or(MISSING_INPUT),
+                path.display()
+            ));
+        }
+        for (key, digest) in &self.env {
+            body.push_str(&format!("env\t{digest}\t{key}\n"));
+        }
+        for output in &self.outputs {
+            body.push_str(&format!(
+                "output\t{}\t{:o}\t{}\n",
+                output.digest,
+                output.mode,
+                output.path.display()
+            ));
+        }
+        if let Some(stdout) = &self.stdout {
+            body.push_str(&format!("stdout\t{stdout}\n"));
+        }
+        if let Some(stderr) = &self.stderr {
+            body.push_str(&format!("stderr\t{stderr}\n"));
+        }
+        if let Some(artifact) = &self.artifact {
+            body.push_str(&format!("artifact\t{artifact}\n"));
+        }
+        body.push_str(&format!("checksum\t{}\n", sha256_hex(body.as_bytes())));
+        body.into_bytes()
+    }
+
+    /// Parse a marker, failing if it is truncated or doesn't match its checksum
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let Ok(content) = std::str::from_utf8(bytes) else {
+            bail!("marker is not valid UTF-8");
+        };
+        let Some(checksum_at) = content.rfind("checksum\t") else {
+            bail!("marker has no checksum, it may be truncated");
+        };
+        let (body, checksum_line) = content.split_at(checksum_at);
+        let checksum = checksum_line.trim_start_matches("checksum\t").trim_end();
+        if (!body.is_empty() && !body.ends_with('\n')) || checksum != sha256_hex(body.as_bytes()) {
+            bail!("marker checksum mismatch");
+        }
+
+        let mut manifest = Manifest::default();
+        for line in body.lines() {
+            let mut fields = line.splitn(3, '\t');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("task"), Some(task), None) => manifest.task = task.to_string(),
+                (Some("created"), Some(secs), None) => {
+                    manifest.created = secs
+                        .parse()
+                        .ok()
+                        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                (Some("duration"), Some(millis), None) => {
+                    manifest.duration = millis.parse().ok().map(Duration::from_millis);
+                }
+                (Some("input"), Some(digest), Some(path)) => manifest.inputs.push((
+                    PathBuf::from(path),
+                    (digest != MISSING_INPUT).then(|| digest.to_string()),
+                )),
+                (Some("env"), Some(digest), Some(key)) => {
+                    manifest.env.push((key.to_string(), digest.to_string()))
+                }
+                (Some("output"), Some(digest), Some(rest)) => {
+                    let output = rest.split_once('\t').and_then(|(mode, path)| {
+                        Some(OutputFile {
+                            path: PathBuf::from(path),
+                            digest: digest.to_string(),
+                            mode: u32::from_str_radix(mode, 8).ok()?,
+                        })
+                    });
+                    match output {
+                        Some(output) => manifest.outputs.push(output),
+                        None => bail!("unrecognized marker line '{}'", line),
+                    }
+                }
+                (Some("stdout"), Some(digest), None) => manifest.stdout = Some(digest.to_string()),
+                (Some("stderr"), Some(digest), None) => manifest.stderr = Some(digest.to_string()),
+                (Some("artifact"), Some(digest), None) => {
+                    manifest.artifact = Some(digest.to_string())
+                }
+                _ => bail!("unrecognized marker line '{}'", line),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Digests of every blob the entry refers to: its outputs and logs
+    pub fn blobs(&self) -> impl Iterator<Item = &String> {
+        self.outputs
+            .iter()
+            .map(|output| &output.digest)
+            .chain(&self.stdout)
+            .chain(&self.stderr)
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Manifest {
+        Manifest {
+            task: "build".to_string(),
+            created: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            duration: Some(Duration::from_millis(1520)),
+            inputs: vec![
+                (PathBuf::from("src/main.rs"), Some("aa".repeat(32))),
+                (PathBuf::from("gone file.txt"), None),
+            ],
+            env: vec![("RUSTFLAGS".to_string(), "bb".repeat(32))],
+            outputs: vec![OutputFile {
+                path: PathBuf::from("dist/app"),
+                digest: "cc".repeat(32),
+                mode: 0o755,
+            }],
+            stdout: Some("dd".repeat(32)),
+            stderr: None,
+            artifact: Some("ee".repeat(32)),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let manifest = sample();
+        let bytes = manifest.to_bytes();
+        let text = String::from_utf8(bytes.clone()).unwrap();
+        assert!(text.starts_with("task\tbuild\ncreated\t1700000000\nduration\t1520\n"));
+        assert!(text.contains(&format!("input\t{MISSING_INPUT}\tgone file.txt\n")));
+        assert!(text.contains("\t755\tdist/app\n"));
+        assert_eq!(Manifest::parse(&bytes).unwrap(), manifest);
+        assert_eq!(manifest.blobs().count(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let text = String::from_utf8(sample().to_bytes()).unwrap();
+
+        let edited = text.replace("duration\t1520", "duration\t1521");
+        assert!(Manifest::parse(edited.as_bytes())
+            .unwrap_err()
+            .to_string()
+            .contains("checksum mismatch"));
+
+        let truncated = &text[..text.find("checksum").unwrap()];
+        assert!(Manifest::parse(truncated.as_bytes()).is_err());
+
+        let cut_checksum = &text[..text.len() - 5];
+        assert!(Manifest::parse(cut_checksum.as_bytes()).is_err());
+
+        assert!(Manifest::parse(b"\xff\xfe").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_lines_with_valid_checksum() {
+        let body = "task\tbuild\nbogus\tline\n";
+        let marker = format!("{body}checksum\t{}\n", sha256_hex(body.as_bytes()));
+        assert!(Manifest::parse(marker.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use crate::config::{Param, ParamType};
+use crate::shell::Shell;
+
+/// Arguments given to the target task on the command line
+#[derive(Debug, Clone, Default)]
+pub struct TaskArgs {
+    pub values: HashMap<String, String>,
+    pub passthrough: Vec<String>,
+}
+
+impl TaskArgs {
+    /// Parse `key=value` arguments and the raw arguments that followed `--`
+    pub fn parse(args: &[String], passthrough: &[String]) -> Result<Self> {
+        let mut values = HashMap::new();
+
+        for arg in args {
+            match arg.split_once('=') {
+                Some((key, value)) if !key.is_empty() => {
+                    values.insert(key.to_string(), value.to_string());
+                }
+                _ => bail!(
+                    "Invalid task argument '{}': expected key=value, or pass raw arguments after --",
+                    arg
+                ),
+            }
+        }
+
+        Ok(Self {
+            values,
+            passthrough: passthrough.to_vec(),
+        })
+    }
+}
+
+/// Resolve the final parameter values for a task, validating them against
+/// the declared parameters before anything runs.
+pub fn resolve(
+    task_name: &str,
+    params: &HashMap<String, Param>,
+    args: &TaskArgs,
+) -> Result<HashMap<String, String>> {
+    for key in args.values.keys() {
+        if !params.contains_key(key) {
+            let mut declared: Vec<_> = params.keys().cloned().collect();
+            declared.sort();
+            bail!(
+                "Task '{}' has no parameter '{}'. Declared parameters: {}",
+                task_name,
+                key,
+                if declared.is_empty() {
+                    "none".to_string()
+                } else {
+                    declared.join(", ")
+                }
+            );
+        }
+    }
+
+    let mut resolved = HashMap::with_capacity(params.len());
+
+    for (name, param) in params {
+        let value = match (args.values.get(name), &param.default) {
+            (Some(value), _) => value.clone(),
+            (None, Some(default)) => default.to_string(),
+            (None, None) => bail!(
+                "Task '{}' requires parameter '{}' (pass it as {}=<value>)",
+                task_name,
+                name,
+                name
+            ),
+        };
+
+        validate(task_name, name, param, &value)?;
+        resolved.insert(name.clone(), value);
+    }
+
+    Ok(resolved)
+}
+
+fn validate(task_name: &str, name: &str, param: &Param, value: &str) -> Result<()> {
+    let valid_type = match param.param_type {
+        ParamType::String => true,
+        ParamType::Int => value.parse::<i64>().is_ok(),
+        ParamType::Float => value.parse::<f64>().is_ok(),
+        ParamType::Bool => matches!(value, "true" | "false"),
+    };
+    if !valid_type {
+        bail!(
+            "Parameter '{}' of task '{}' must be of type {}, got '{}'",
+            name,
+            task_name,
+            param.param_type,
+            value
+        );
+    }
+
+    if !param.allowed.is_empty() && !param.allowed.iter().any(|a| a.to_string() == value) {
+        bail!(
+            "Parameter '{}' of task '{}' must be one of: {} (got '{}')",
+            name,
+            task_name,
+            param
+                .allowed
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            value
+        );
+    }
+
+    Ok(())
+}
+
+/// Append passthrough arguments to a command, quoted for the shell that runs it
+pub fn append_passthrough(cmd: &str, passthrough: &[String], shell: Shell) -> Result<String> {
+    let mut applied = cmd.to_string();
+
+    for arg in passthrough {
+        applied.push(' ');
+        applied.push_str(&shell.quote(arg)?);
+    }
+
+    Ok(applied)
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+
+use crate::graph::Task;
+
+/// What a task holds while it runs: `--jobs` slots and units of named
+/// resources
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Demand {
+    pub jobs: u64,
+    pub named: BTreeMap<String, u64>,
+}
+
+/// Units still free in the job pool and each named resource, tracked by the
+/// parallel scheduler as tasks start and finish
+#[derive(Debug)]
+pub struct Pools {
+    jobs: u64,
+    free_jobs: u64,
+    free: BTreeMap<String, u64>,
+}
+
+impl Pools {
+    pub fn new(jobs: u64, resources: &BTreeMap<String, u64>) -> Self {
+        Self {
+            jobs,
+            free_jobs: jobs,
+            free: resources.clone(),
+        }
+    }
+
+    /// What `task` needs. A weight above `--jobs` takes every slot, so the
+    /// task runs alone instead of never.
+    pub fn demand(&self, task: &Task) -> Demand {
+        Demand {
+            jobs: task.weight.min(self.jobs),
+            named: task.resources.clone(),
+        }
+    }
+
+    /// Take what `demand` needs if all of it is free
+    pub fn try_take(&mut self, demand: &Demand) -> bool {
+        let fits = demand.jobs <= self.free_jobs
+            && demand
+                .named
+                .iter()
+                .all(|(name, amount)| self.free.get(name).is_some_and(|free| free >= amount));
+        if fits {
+            self.free_jobs -= demand.jobs;
+            for (name, amount) in &demand.named {
+                if let Some(free) = self.free.get_mut(name) {
+                    *free -= amount;
+                }
+            }
+        }
+        fits
+    }
+
+    pub fn release(&mut self, demand: &Demand) {
+        self.free_jobs += demand.jobs;
+        for (name, amount) in &demand.named {
+            if let Some(free) = self.free.get_mut(name) {
+                *free += amount;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demand(jobs: u64, named: &[(&str, u64)]) -> Demand {
+        Demand {
+            jobs,
+            named: named.iter().map(|(n, a)| (n.to_string(), *a)).collect(),
+        }
+    }
+
+    #[test]
+    fn takes_only_when_everything_fits() {
+        let resources = BTreeMap::from([("db".to_string(), 1)]);
+        let mut pools = Pools::new(4, &resources);
+
+        let migrate = demand(1, &[("db", 1)]);
+        assert!(pools.try_take(&migrate));
+        // The job slots are free, but the database isn't
+        assert!(!pools.try_take(&demand(1, &[("db", 1)])));
+        assert!(pools.try_take(&demand(3, &[])));
+        assert!(!pools.try_take(&demand(1, &[])));
+
+        pools.release(&migrate);
+        assert!(pools.try_take(&demand(1, &[("db", 1)])));
+    }
+
+    #[test]
+    fn failed_take_holds_nothing() {
+        let mut pools = Pools::new(2, &BTreeMap::new());
+        assert!(!pools.try_take(&demand(1, &[("unknown", 1)])));
+        assert!(pools.try_take(&demand(2, &[])));
+    }
+}
@@ -0,0 +1,271 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::fsutil::{self, normalize};
+use crate::graph::Task;
+
+/// How a `sandbox:` task is confined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sandbox {
+    /// Keep the host network instead of an empty network namespace
+    pub network: bool,
+}
+
+/// Private tmpfs mount point of one sandboxed command. The mount itself goes
+/// away with the command's namespace; dropping this removes the empty
+/// directory it was mounted on.
+pub struct Scratch {
+    path: PathBuf,
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+/// Directories a sandboxed task may write to: the directory part of each of
+/// its output patterns, created if missing
+fn writable_dirs(task_name: &str, root: &Path, outputs: &[String]) -> Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+
+    for pattern in outputs {
+        if pattern.starts_with('!') {
+            continue;
+        }
+
+        // Everything up to the first component with a wildcard
+        let literal: PathBuf = Path::new(pattern)
+            .components()
+            .take_while(|part| !part.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+            .collect();
+        let literal = root.join(literal);
+        let dir = if literal.is_dir() || pattern.contains(['*', '?', '[']) {
+            literal
+        } else {
+            match literal.parent() {
+                Some(parent) => parent.to_path_buf(),
+                None => literal,
+            }
+        };
+
+        let dir = normalize(&dir);
+        if !dir.starts_with(root) {
+            // Only the project is mounted read-only
+            continue;
+        }
+        if dir == root {
+            bail!(
+                "Output '{}' of sandboxed task '{}' must be inside a directory, since the project root stays read-only",
+                pattern,
+                task_name
+            );
+        }
+
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+        if !dirs.iter().any(|existing| dir.starts_with(existing)) {
+            dirs.retain(|existing| !existing.starts_with(&dir));
+            dirs.push(dir);
+        }
+    }
+
+    Ok(dirs)
+}
+
+/// Explain a failure to start a sandboxed command. Setting up the namespaces
+/// fails with these errors when the kernel doesn't let unprivileged users
+/// create them.
+pub fn spawn_error(task_name: &str, err: std::io::Error) -> anyhow::Error {
+    let namespaces_refused = matches!(
+        err.raw_os_error(),
+        Some(libc::EPERM | libc::EACCES | libc::EINVAL | libc::ENOSPC | libc::EUSERS)
+    );
+    let err = anyhow::Error::new(err)
+        .context(format!("Failed to start task '{task_name}' in its sandbox"));
+    if namespaces_refused {
+        err.context(
+            "Could not create the sandbox's namespaces: unprivileged user namespaces seem to be \
+             disabled on this system (check the sysctls user.max_user_namespaces, \
+             kernel.unprivileged_userns_clone or kernel.apparmor_restrict_unprivileged_userns), \
+             or run the task without `sandbox`",
+        )
+    } else {
+        err
+    }
+}
+
+/// Make `cmd` start in new user, mount and (unless allowed) network
+/// namespaces, with the project read-only except the task's outputs and a
+/// private tmpfs as `TMPDIR`
+#[cfg(target_os = "linux")]
+pub fn apply(cmd: &mut Command, task: &Task, sandbox: Sandbox) -> Result<Scratch> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn c_path(path: &Path) -> Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("Path {} contains a NUL byte", path.display()))
+    }
+
+    // The child runs this between fork and exec, where allocating isn't
+    // safe, so everything it needs is prepared here
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+    let uid_map = format!("{uid} {uid} 1\n");
+    let gid_map = format!("{gid} {gid} 1\n");
+    let uid_map_file = c_path(Path::new("/proc/self/uid_map"))?;
+    let gid_map_file = c_path(Path::new("/proc/self/gid_map"))?;
+    let setgroups_file = c_path(Path::new("/proc/self/setgroups"))?;
+
+    let root = std::env::current_dir()?;
+    let root_flags = locked_flags(&root)?;
+    let root_path = c_path(&root)?;
+    let writable = writable_dirs(&task.name, &root, &task.outputs)?
+        .iter()
+        .map(|dir| c_path(dir))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The working directory has to be entered again once the project is
+    // remounted, or the command would keep writing through the old mount
+    let cwd = match cmd.as_std().get_current_dir() {
+        Some(dir) => root.join(dir),
+        None => root.to_path_buf(),
+    };
+    let cwd = c_path(&cwd)?;
+
+    let scratch = Scratch {
+        path: fsutil::create_private_temp_dir("sandbox", &task.name)
+            .context("Failed to create the sandbox's scratch directory")?,
+    };
+    let scratch_path = c_path(&scratch.path)?;
+    cmd.env("TMPDIR", &scratch.path);
+
+    let mut namespaces = libc::CLONE_NEWUSER | libc::CLONE_NEWNS;
+    if !sandbox.network {
+        namespaces |= libc::CLONE_NEWNET;
+    }
+
+    let setup = move || -> std::io::Result<()> {
+        let check = |result: libc::c_int| {
+            if result == -1 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+        let write_file = |path: &CString, contents: &[u8]| unsafe {
+            let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            check(fd)?;
+            let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+            libc::close(fd);
+            if written == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        };
+        let mount = |source: *const libc::c_char,
+                     target: &CString,
+                     fstype: *const libc::c_char,
+                     flags: libc::c_ulong| unsafe {
+            check(libc::mount(
+                source,
+                target.as_ptr(),
+                fstype,
+                flags,
+                std::ptr::null(),
+            ))
+        };
+
+        unsafe { check(libc::unshare(namespaces))? };
+        write_file(&uid_map_file, uid_map.as_bytes())?;
+        write_file(&setgroups_file, b"deny")?;
+        write_file(&gid_map_file, gid_map.as_bytes())?;
+
+        // Keep every mount below private to this namespace
+        let slash = c"/";
+        unsafe {
+            check(libc::mount(
+                std::ptr::null(),
+                slash.as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+        }
+
+        // Bind the outputs onto a copy of the project first, so making the
+        // copy read-only leaves them writable
+        mount(
+            root_path.as_ptr(),
+            &root_path,
+            std::ptr::null(),
+            libc::MS_BIND | libc::MS_REC,
+        )?;
+        for dir in &writable {
+            mount(
+                dir.as_ptr(),
+                dir,
+                std::ptr::null(),
+                libc::MS_BIND | libc::MS_REC,
+            )?;
+        }
+        mount(
+            std::ptr::null(),
+            &root_path,
+            std::ptr::null(),
+            libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | root_flags,
+        )?;
+
+        let tmpfs = c"tmpfs";
+        mount(
+            tmpfs.as_ptr(),
+            &scratch_path,
+            tmpfs.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+        )?;
+
+        unsafe { check(libc::chdir(cwd.as_ptr())) }
+    };
+    unsafe {
+        cmd.pre_exec(setup);
+    }
+
+    Ok(scratch)
+}
+
+/// Flags of the mount holding `path` that a user namespace may not clear,
+/// and so must be repeated when remounting it
+#[cfg(target_os = "linux")]
+fn locked_flags(path: &Path) -> Result<libc::c_ulong> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Path {} contains a NUL byte", path.display()))?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } == -1 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to inspect the mount of {}", path.display()));
+    }
+
+    let mapping = [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ];
+    Ok(mapping
+        .iter()
+        .filter(|(statvfs_flag, _)| stats.f_flag & statvfs_flag != 0)
+        .fold(0, |flags, (_, mount_flag)| flags | mount_flag))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_cmd: &mut Command, _task: &Task, _sandbox: Sandbox) -> Result<Scratch> {
+    bail!("sandbox is only supported on Linux");
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use crate::graph::TaskGraph;
+
+/// Variable name patterns that are always treated as secrets
+pub const DEFAULT_SECRET_PATTERNS: &[&str] = &["*_TOKEN", "*_SECRET", "*_PASSWORD", "*_API_KEY"];
+
+/// Values shorter than this are masked in environment dumps but not in free
+/// text, where masking e.g. `1` would make all output unreadable
+const MIN_REDACTED_LEN: usize = 4;
+
+pub const MASK: &str = "***";
+
+/// Whether a variable name matches any of the secret patterns. Patterns are
+/// exact names or use `*` as a wildcard.
+pub fn is_secret_name(name: &str, patterns: &[String]) -> bool {
+    DEFAULT_SECRET_PATTERNS
+        .iter()
+        .copied()
+        .chain(patterns.iter().map(String::as_str))
+        .any(|pattern| wildcard_match(pattern, name))
+}
+
+/// Whether a name matches a pattern that is an exact name or uses `*` as a
+/// wildcard
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if name.len() < first.len() + last.len() || !name.starts_with(first) || !name.ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
+
+/// Replaces known secret values with `***` in text that is about to be shown
+/// or stored
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    secrets: Arc<Vec<String>>,
+}
+
+impl Redactor {
+    /// Collect secret values from every task's environment, plus inherited
+    /// environment variables matching the global secret patterns
+    pub fn from_graph(graph: &TaskGraph, global_patterns: &[String]) -> Self {
+        let mut secrets: Vec<String> = std::env::vars()
+            .filter(|(key, _)| is_secret_name(key, global_patterns))
+            .map(|(_, value)| value)
+            .collect();
+
+        for task in graph.tasks.values() {
+            secrets.extend(
+                task.env
+                    .iter()
+                    .filter(|(key, _)| task.is_secret(key))
+                    .map(|(_, value)| value.clone()),
+            );
+        }
+
+        Self::new(secrets)
+    }
+
+    pub fn from_process_env(global_patterns: &[String]) -> Self {
+        Self::new(
+            std::env::vars()
+                .filter(|(key, _)| is_secret_name(key, global_patterns))
+                .map(|(_, value)| value)
+                .collect(),
+        )
+    }
+
+    fn new(mut secrets: Vec<String>) -> Self {
+        secrets.retain(|value| value.len() >= MIN_REDACTED_LEN);
+        // Replace longer values first so a secret containing another is fully masked
+        secrets.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        secrets.dedup();
+
+        Self {
+            secrets: Arc::new(secrets),
+        }
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut redacted = text.to_string();
+        for secret in self.secrets.iter() {
+            if redacted.contains(secret.as_str()) {
+                redacted = redacted.replace(secret.as_str(), MASK);
+            }
+        }
+        redacted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("API_TOKEN", "API_TOKEN"));
+        assert!(!wildcard_match("API_TOKEN", "API_TOKENS"));
+        assert!(wildcard_match("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(!wildcard_match("*_TOKEN", "_TOKENX"));
+        assert!(wildcard_match("AWS_*", "AWS_SECRET_ACCESS_KEY"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("A*B*C", "AxxBxxC"));
+        assert!(!wildcard_match("A*B*C", "AxxCxxB"));
+        // The prefix and suffix may not overlap
+        assert!(!wildcard_match("AB*BC", "ABC"));
+    }
+
+    #[test]
+    fn default_patterns_apply() {
+        assert!(is_secret_name("NPM_TOKEN", &[]));
+        assert!(!is_secret_name("HOME", &[]));
+        assert!(is_secret_name("HOME", &["HO*".to_string()]));
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::backend::{self, DirectoryBackend, EntryKind};
+
+const WORKER_THREADS: usize = 8;
+
+/// Options for `rush cache serve`
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    pub addr: String,
+    pub dir: PathBuf,
+    /// Refuse every upload, serving only what is already stored
+    pub read_only: bool,
+    /// Bearer token uploads must carry; downloads are always allowed
+    pub write_token: Option<String>,
+    /// Largest upload accepted, in bytes
+    pub max_upload: u64,
+}
+
+/// Serve a directory as an HTTP cache backend until the process is stopped
+pub fn serve(options: ServeOptions) -> Result<()> {
+    for kind in [EntryKind::Marker, EntryKind::Artifact] {
+        fs::create_dir_all(options.dir.join(kind.as_str()))
+            .with_context(|| format!("Failed to create {}", options.dir.display()))?;
+    }
+
+    let server = Arc::new(
+        Server::http(&options.addr)
+            .map_err(|e| anyhow!("Failed to listen on {}: {}", options.addr, e))?,
+    );
+    println!(
+        "🗄️  Serving cache from {} on http://{}{}",
+        options.dir.display(),
+        server.server_addr(),
+        if options.read_only {
+            " (read-only)"
+        } else {
+            ""
+        }
+    );
+
+    // Uploads are checked by the server, so the store itself is writable
+    let store = Arc::new(DirectoryBackend::new(&options.dir, false));
+    let options = Arc::new(options);
+    let workers: Vec<_> = (0..WORKER_THREADS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let store = Arc::clone(&store);
+            let options = Arc::clone(&options);
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle(request, &store, &options);
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+fn handle(mut request: Request, store: &DirectoryBackend, options: &ServeOptions) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+
+    let status = match parse_url(&url) {
+        None => 404,
+        Some((kind, key)) => match method {
+            Method::Get | Method::Head => match store.open(kind, key) {
+                Ok(Some(file)) => {
+                    println!("{method} {url} 200");
+                    let _ = request.respond(Response::from_file(file));
+                    return;
+                }
+                Ok(None) => 404,
+                Err(e) => {
+                    eprintln!("⚠️  Failed to read {url}: {e:#}");
+                    500
+                }
+            },
+            Method::Put if options.read_only => 403,
+            Method::Put if !authorized(&request, options) => 401,
+            Method::Put
+                if request
+                    .body_length()
+                    .is_some_and(|length| length as u64 > options.max_upload) =>
+            {
+                413
+            }
+            Method::Put => {
+                let mut body = LimitedBody {
+                    inner: request.as_reader(),
+                    remaining: options.max_upload,
+                    exceeded: false,
+                };
+                match store.store(kind, key, &mut body) {
+                    Ok(()) => 201,
+                    Err(_) if body.exceeded => 413,
+                    Err(e) => {
+                        eprintln!("⚠️  Failed to store {url}: {e:#}");
+                        500
+                    }
+                }
+            }
+            _ => 405,
+        },
+    };
+
+    println!("{method} {url} {status}");
+    let _ = request.respond(Response::empty(status));
+}
+
+/// Request body that fails once more than `remaining` bytes arrive, which
+/// catches oversized bodies sent without a length before they are stored
+struct LimitedBody<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> Read for LimitedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "upload is too large",
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Entry for `/<kind>/<hash>`, or `None` for any other URL
+fn parse_url(url: &str) -> Option<(EntryKind, &str)> {
+    let (kind, key) = url.trim_start_matches('/').split_once('/')?;
+    let kind = EntryKind::parse(kind)?;
+    backend::is_valid_key(key).then_some((kind, key))
+}
+
+fn authorized(request: &Request, options: &ServeOptions) -> bool {
+    let Some(token) = &options.write_token else {
+        return true;
+    };
+    let expected = format!("Bearer {token}");
+    request
+        .headers()
+        .iter()
+        .any(|header: &Header| header.field.equiv("Authorization") && header.value == *expected)
+}
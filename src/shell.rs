@@ -0,0 +1,169 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::process::Command;
+
+/// Shell used to run a task's command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Shell {
+    Sh,
+    Bash,
+    Zsh,
+    Pwsh,
+    Cmd,
+    /// Run the command directly, splitting it into arguments like a shell would
+    None,
+}
+
+impl Shell {
+    /// `sh` everywhere except Windows, which uses `cmd`
+    pub fn platform_default() -> Self {
+        if cfg!(target_os = "windows") {
+            Shell::Cmd
+        } else {
+            Shell::Sh
+        }
+    }
+
+    pub fn command(self, script: &str) -> Result<Command> {
+        let (program, args): (&str, &[&str]) = match self {
+            Shell::Sh => ("sh", &["-c"]),
+            Shell::Bash => ("bash", &["-c"]),
+            Shell::Zsh => ("zsh", &["-c"]),
+            Shell::Pwsh => ("pwsh", &["-NoProfile", "-NonInteractive", "-Command"]),
+            Shell::Cmd => ("cmd", &["/C"]),
+            Shell::None => {
+                let argv = shlex::split(script)
+                    .ok_or_else(|| anyhow!("Unbalanced quotes in command: {}", script))?;
+                let (program, args) = argv.split_first().ok_or_else(|| anyhow!("Empty command"))?;
+                let mut cmd = Command::new(program);
+                cmd.args(args);
+                return Ok(cmd);
+            }
+        };
+
+        let mut cmd = Command::new(program);
+        cmd.args(args).arg(script);
+        Ok(cmd)
+    }
+
+    /// Quote a value so the shell passes it to the command as one argument
+    /// and never runs any of it. `cmd` has no quoting that turns off `%`
+    /// expansion or stray `"`, so values with those are refused.
+    pub fn quote(self, value: &str) -> Result<String> {
+        match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh | Shell::None => {
+                Ok(shlex::try_quote(value)?.into_owned())
+            }
+            // PowerShell also reads typographic single quotes as quotes
+            Shell::Pwsh => {
+                let mut quoted = String::with_capacity(value.len() + 2);
+                quoted.push('\'');
+                for c in value.chars() {
+                    if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+                        quoted.push(c);
+                    }
+                    quoted.push(c);
+                }
+                quoted.push('\'');
+                Ok(quoted)
+            }
+            Shell::Cmd => {
+                if let Some(c) = value
+                    .chars()
+                    .find(|c| matches!(c, '"' | '%' | '!' | '\n' | '\r' | '\0'))
+                {
+                    bail!(
+                        "Can't quote {:?} for cmd, which would expand or run part of it (found {:?}); use another shell for this task",
+                        value,
+                        c
+                    );
+                }
+                // Inside double quotes `&`, `|`, `<`, `>` and `^` are plain
+                // text. Trailing backslashes are doubled so they don't escape
+                // the closing quote for the program reading its arguments.
+                let trailing = value.len() - value.trim_end_matches('\\').len();
+                Ok(format!("\"{}{}\"", value, "\\".repeat(trailing)))
+            }
+        }
+    }
+
+    /// Command that runs a script file with this shell
+    pub fn script_command(self, path: &Path) -> Command {
+        let mut cmd = match self {
+            Shell::Sh => Command::new("sh"),
+            Shell::Bash => Command::new("bash"),
+            Shell::Zsh => Command::new("zsh"),
+            Shell::Pwsh => {
+                let mut cmd = Command::new("pwsh");
+                cmd.args(["-NoProfile", "-NonInteractive", "-File"]);
+                cmd
+            }
+            Shell::Cmd => {
+                let mut cmd = Command::new("cmd");
+                cmd.arg("/C");
+                cmd
+            }
+            Shell::None => return Shell::platform_default().script_command(path),
+        };
+        cmd.arg(path);
+        cmd
+    }
+
+    /// File extension scripts for this shell need to be runnable
+    pub fn script_extension(self) -> &'static str {
+        match self {
+            Shell::Pwsh => "ps1",
+            Shell::Cmd => "bat",
+            Shell::None => Shell::platform_default().script_extension(),
+            Shell::Sh | Shell::Bash | Shell::Zsh => "sh",
+        }
+    }
+}
+
+/// File extension to use for an inline script run by `interpreter`
+pub fn interpreter_extension(interpreter: &str) -> &'static str {
+    let program = Path::new(interpreter)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(interpreter);
+
+    match program {
+        p if p.starts_with("python") => "py",
+        "node" | "deno" | "bun" => "js",
+        "ruby" => "rb",
+        "perl" => "pl",
+        "pwsh" | "powershell" => "ps1",
+        "cmd" => "bat",
+        _ => "sh",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posix_quoting_keeps_one_word() {
+        assert_eq!(Shell::Sh.quote("a b; rm").unwrap(), "'a b; rm'");
+        assert_eq!(Shell::Bash.quote("plain").unwrap(), "plain");
+        assert_eq!(Shell::None.quote("it's").unwrap(), "\"it's\"");
+    }
+
+    #[test]
+    fn pwsh_doubles_single_quotes() {
+        assert_eq!(Shell::Pwsh.quote("a b").unwrap(), "'a b'");
+        assert_eq!(Shell::Pwsh.quote("it's $x").unwrap(), "'it''s $x'");
+        assert_eq!(Shell::Pwsh.quote("\u{2019}").unwrap(), "'\u{2019}\u{2019}'");
+    }
+
+    #[test]
+    fn cmd_quotes_operators_and_refuses_expansion() {
+        assert_eq!(Shell::Cmd.quote("x&calc").unwrap(), "\"x&calc\"");
+        assert_eq!(Shell::Cmd.quote("C:\\dir\\").unwrap(), "\"C:\\dir\\\\\"");
+        assert!(Shell::Cmd.quote("%PATH%").is_err());
+        assert!(Shell::Cmd.quote("a\" & calc").is_err());
+        assert!(Shell::Cmd.quote("!x!").is_err());
+    }
+}
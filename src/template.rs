@@ -0,0 +1,870 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::process::Command;
+
+use crate::config::RushConfig;
+use crate::shell::Shell;
+
+/// Renders `{{ ... }}` templates in task fields after the config is parsed.
+///
+/// Templates can reference top-level `vars`, other tasks' properties
+/// (`tasks.build.cmd`), task parameters (`params.env`), environment
+/// variables (`env.HOME`) and built-ins (`os`, `arch`, `cpu_count`,
+/// `git.sha`, `git.short_sha`, `git.branch`). Conditionals use
+/// `{{ if expr }} ... {{ else if expr }} ... {{ else }} ... {{ end }}`.
+///
+/// Vars whose value is `$(command)` are evaluated lazily, the first time
+/// they are referenced, and the result is reused for the rest of the run.
+pub struct TemplateEngine<'a> {
+    config: &'a RushConfig,
+    resolved: RefCell<HashMap<String, String>>,
+    resolving: RefCell<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Str(s) => !s.is_empty() && s != "false" && s != "0",
+            Value::Num(n) => *n != 0.0,
+            Value::Bool(b) => *b,
+            Value::Null => false,
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Num(n) => Some(*n),
+            Value::Str(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Num(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Null => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Expr(Expr),
+    If {
+        branches: Vec<(Expr, Vec<Node>)>,
+        otherwise: Vec<Node>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Path(Vec<String>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+impl<'a> TemplateEngine<'a> {
+    pub fn new(config: &'a RushConfig) -> Self {
+        Self {
+            config,
+            resolved: RefCell::new(HashMap::new()),
+            resolving: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Render a template string with the given task parameters in scope
+    pub fn render(&self, template: &str, params: &HashMap<String, String>) -> Result<String> {
+        self.render_with(template, params, None)
+    }
+
+    /// Render a command for `shell`. Parameter values come from the command
+    /// line, so `{{ params.x }}` is quoted like passthrough arguments and
+    /// can't split into several words or run commands of its own.
+    pub fn render_command(
+        &self,
+        template: &str,
+        params: &HashMap<String, String>,
+        shell: Shell,
+    ) -> Result<String> {
+        self.render_with(template, params, Some(shell))
+    }
+
+    fn render_with(
+        &self,
+        template: &str,
+        params: &HashMap<String, String>,
+        quote_for: Option<Shell>,
+    ) -> Result<String> {
+        let nodes = parse_template(template)?;
+        let mut output = String::with_capacity(template.len());
+        self.render_nodes(&nodes, params, quote_for, &mut output)?;
+        Ok(output)
+    }
+
+    /// Evaluate a boolean expression such as `os == "linux" and env.CI == "true"`
+    pub fn evaluate(&self, expression: &str, params: &HashMap<String, String>) -> Result<bool> {
+        let expr = parse_expression(expression)?;
+        Ok(self.eval(&expr, params, false)?.is_truthy())
+    }
+
+    fn render_nodes(
+        &self,
+        nodes: &[Node],
+        params: &HashMap<String, String>,
+        quote_for: Option<Shell>,
+        output: &mut String,
+    ) -> Result<()> {
+        for node in nodes {
+            match node {
+                // Expanded after parsing, so a `{{` in a variable stays text
+                Node::Text(text) => output.push_str(&expand_env_vars(text)),
+                Node::Expr(expr) => {
+                    let value = self.eval(expr, params, true)?.to_string();
+                    match (expr, quote_for) {
+                        (Expr::Path(path), Some(shell)) if path[0] == "params" => {
+                            output.push_str(&shell.quote(&value)?)
+                        }
+                        _ => output.push_str(&value),
+                    }
+                }
+                Node::If {
+                    branches,
+                    otherwise,
+                } => {
+                    let mut taken = false;
+                    for (condition, body) in branches {
+                        if self.eval(condition, params, false)?.is_truthy() {
+                            self.render_nodes(body, params, quote_for, output)?;
+                            taken = true;
+                            break;
+                        }
+                    }
+                    if !taken {
+                        self.render_nodes(otherwise, params, quote_for, output)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate an expression. With `strict`, undefined references are errors
+    /// rather than null, which is what interpolation wants.
+    fn eval(&self, expr: &Expr, params: &HashMap<String, String>, strict: bool) -> Result<Value> {
+        Ok(match expr {
+            Expr::Literal(value) => value.clone(),
+            Expr::Path(path) => match self.lookup(path, params)? {
+                Some(value) => value,
+                None if strict => bail!("Undefined template variable '{}'", path.join(".")),
+                None => Value::Null,
+            },
+            Expr::Not(inner) => Value::Bool(!self.eval(inner, params, false)?.is_truthy()),
+            Expr::And(lhs, rhs) => Value::Bool(
+                self.eval(lhs, params, false)?.is_truthy()
+                    && self.eval(rhs, params, false)?.is_truthy(),
+            ),
+            Expr::Or(lhs, rhs) => Value::Bool(
+                self.eval(lhs, params, false)?.is_truthy()
+                    || self.eval(rhs, params, false)?.is_truthy(),
+            ),
+            Expr::Compare(lhs, op, rhs) => {
+                let lhs = self.eval(lhs, params, false)?;
+                let rhs = self.eval(rhs, params, false)?;
+                Value::Bool(compare(&lhs, *op, &rhs))
+            }
+        })
+    }
+
+    fn lookup(&self, path: &[String], params: &HashMap<String, String>) -> Result<Option<Value>> {
+        let segments: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+
+        Ok(match segments.as_slice() {
+            ["os"] => Some(Value::Str(std::env::consts::OS.to_string())),
+            ["arch"] => Some(Value::Str(std::env::consts::ARCH.to_string())),
+            ["cpu_count"] => Some(Value::Num(
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1) as f64,
+            )),
+            ["git", field] => Some(Value::Str(self.git(field)?)),
+            ["env", name] => std::env::var(name).ok().map(Value::Str),
+            ["params", name] => params.get(*name).cloned().map(Value::Str),
+            ["vars", name] => self.var(name)?.map(Value::Str),
+            ["tasks", task, field] => Some(Value::Str(self.task_field(task, field)?)),
+            [name] if self.config.vars.contains_key(*name) => self.var(name)?.map(Value::Str),
+            _ => bail!("Unknown template variable '{}'", path.join(".")),
+        })
+    }
+
+    fn var(&self, name: &str) -> Result<Option<String>> {
+        let Some(raw) = self.config.vars.get(name).map(|v| v.to_string()) else {
+            return Ok(None);
+        };
+
+        let key = format!("vars.{name}");
+        self.resolve_once(&key, || {
+            let trimmed = raw.trim();
+            match trimmed
+                .strip_prefix("$(")
+                .and_then(|rest| rest.strip_suffix(')'))
+            {
+                Some(command) => {
+                    run_capture(command).with_context(|| format!("Failed to evaluate var '{name}'"))
+                }
+                None => self
+                    .render(&raw, &HashMap::new())
+                    .with_context(|| format!("Failed to render var '{name}'")),
+            }
+        })
+        .map(Some)
+    }
+
+    fn task_field(&self, task_name: &str, field: &str) -> Result<String> {
+        let task = self
+            .config
+            .tasks
+            .get(task_name)
+            .ok_or_else(|| anyhow!("Template references unknown task '{}'", task_name))?;
+
+        let key = format!("tasks.{task_name}.{field}");
+        self.resolve_once(&key, || {
+            let defaults = crate::params::resolve(task_name, &task.params, &Default::default())?;
+            match field {
+                "cmd" => self.render(&task.command_summary(), &defaults),
+                "description" => self.render(task.description.as_deref().unwrap_or(""), &defaults),
+                "deps" => Ok(task.deps.join(" ")),
+                "source" => Ok(task.source.display().to_string()),
+                _ => bail!("Tasks have no property '{}' available to templates", field),
+            }
+        })
+    }
+
+    fn git(&self, field: &str) -> Result<String> {
+        let args: &[&str] = match field {
+            "sha" => &["rev-parse", "HEAD"],
+            "short_sha" => &["rev-parse", "--short", "HEAD"],
+            "branch" => &["rev-parse", "--abbrev-ref", "HEAD"],
+            _ => bail!("Unknown template variable 'git.{}'", field),
+        };
+
+        self.resolve_once(&format!("git.{field}"), || {
+            let output = Command::new("git")
+                .args(args)
+                .output()
+                .context("Failed to run git")?;
+            if !output.status.success() {
+                bail!("git.{} is unavailable: not a git repository", field);
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+    }
+
+    /// Compute a value at most once per run, detecting reference cycles
+    fn resolve_once(&self, key: &str, compute: impl FnOnce() -> Result<String>) -> Result<String> {
+        if let Some(value) = self.resolved.borrow().get(key) {
+            return Ok(value.clone());
+        }
+
+        if self.resolving.borrow().iter().any(|k| k == key) {
+            let chain = self.resolving.borrow().join(" -> ");
+            bail!("Circular template reference: {} -> {}", chain, key);
+        }
+
+        self.resolving.borrow_mut().push(key.to_string());
+        let result = compute();
+        self.resolving.borrow_mut().pop();
+
+        let value = result?;
+        self.resolved
+            .borrow_mut()
+            .insert(key.to_string(), value.clone());
+        Ok(value)
+    }
+}
+
+fn run_capture(command: &str) -> Result<String> {
+    let output = if cfg!(target_os = "windows") {
+        Command::new("cmd").args(["/C", command]).output()?
+    } else {
+        Command::new("sh").args(["-c", command]).output()?
+    };
+
+    if !output.status.success() {
+        bail!(
+            "Command '{}' failed with exit code: {:?}",
+            command,
+            output.status.code()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn compare(lhs: &Value, op: CompareOp, rhs: &Value) -> bool {
+    match op {
+        CompareOp::Eq => values_equal(lhs, rhs),
+        CompareOp::Ne => !values_equal(lhs, rhs),
+        _ => {
+            let ordering = match (lhs.as_number(), rhs.as_number()) {
+                (Some(a), Some(b)) => a.partial_cmp(&b),
+                _ => Some(lhs.to_string().cmp(&rhs.to_string())),
+            };
+            match ordering {
+                Some(ordering) => match op {
+                    CompareOp::Lt => ordering.is_lt(),
+                    CompareOp::Le => ordering.is_le(),
+                    CompareOp::Gt => ordering.is_gt(),
+                    CompareOp::Ge => ordering.is_ge(),
+                    CompareOp::Eq | CompareOp::Ne => unreachable!(),
+                },
+                None => false,
+            }
+        }
+    }
+}
+
+fn values_equal(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::Null, Value::Null) => true,
+        (Value::Null, _) | (_, Value::Null) => false,
+        _ => match (lhs.as_number(), rhs.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ => lhs.to_string() == rhs.to_string(),
+        },
+    }
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` from the process environment
+pub fn expand_env_vars(content: &str) -> String {
+    // Handle ${VAR:-default} syntax
+    let default_regex = regex::Regex::new(r"\$\{([^}]+):-([^}]*)\}").unwrap();
+    let mut expanded = default_regex
+        .replace_all(content, |caps: &regex::Captures| {
+            let var_name = &caps[1];
+            let default_value = &caps[2];
+            std::env::var(var_name).unwrap_or_else(|_| default_value.to_string())
+        })
+        .to_string();
+
+    // Handle regular ${VAR} syntax
+    let var_regex = regex::Regex::new(r"\$\{([^}]+)\}").unwrap();
+    expanded = var_regex
+        .replace_all(&expanded, |caps: &regex::Captures| {
+            let var_name = &caps[1];
+            std::env::var(var_name).unwrap_or_else(|_| format!("${{{var_name}}}"))
+        })
+        .to_string();
+
+    expanded
+}
+
+enum Tag {
+    Expr(Expr),
+    If(Expr),
+    ElseIf(Expr),
+    Else,
+    End,
+}
+
+/// Split a template into text and tags. `{{{{` stands for a literal `{{`,
+/// and a `{{` that doesn't start a valid tag is kept as text, so commands
+/// like `docker ps --format '{{.Names}}'` pass through unchanged.
+fn parse_template(template: &str) -> Result<Vec<Node>> {
+    let mut tags = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let after_open = &rest[start + 2..];
+        if let Some(after_escape) = after_open.strip_prefix("{{") {
+            text.push_str(&rest[..start + 2]);
+            rest = after_escape;
+            continue;
+        }
+
+        let tag = find_tag_end(after_open).and_then(|end| {
+            parse_tag(after_open[..end].trim())
+                .ok()
+                .map(|tag| (tag, end))
+        });
+        match tag {
+            Some((tag, end)) => {
+                text.push_str(&rest[..start]);
+                tags.push(Err(std::mem::take(&mut text)));
+                tags.push(Ok(tag));
+                rest = &after_open[end + 2..];
+            }
+            None => {
+                text.push_str(&rest[..start + 2]);
+                rest = after_open;
+            }
+        }
+    }
+    text.push_str(rest);
+    tags.push(Err(text));
+
+    let mut iter = tags.into_iter().peekable();
+    let nodes = parse_block(&mut iter, false)?;
+    Ok(nodes)
+}
+
+/// Find the closing `}}` of a tag, skipping over string literals
+fn find_tag_end(tag: &str) -> Option<usize> {
+    let bytes = tag.as_bytes();
+    let mut quote = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(_), b'\\') => i += 1,
+            (Some(q), c) if c == q => quote = None,
+            (None, b'"' | b'\'') => quote = Some(bytes[i]),
+            (None, b'}') if bytes.get(i + 1) == Some(&b'}') => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+
+    None
+}
+
+fn parse_tag(tag: &str) -> Result<Tag> {
+    let keyword = |word: &str| {
+        tag.strip_prefix(word)
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+            .map(str::trim)
+    };
+
+    if let Some(condition) = keyword("if") {
+        return Ok(Tag::If(parse_expression(condition)?));
+    }
+    if let Some(rest) = keyword("else") {
+        return match rest.strip_prefix("if") {
+            Some(condition) if condition.starts_with(char::is_whitespace) => {
+                Ok(Tag::ElseIf(parse_expression(condition.trim())?))
+            }
+            _ if rest.is_empty() => Ok(Tag::Else),
+            _ => bail!("Invalid template tag '{{{{ {} }}}}'", tag),
+        };
+    }
+    if keyword("end").is_some_and(str::is_empty) {
+        return Ok(Tag::End);
+    }
+
+    Ok(Tag::Expr(parse_expression(tag)?))
+}
+
+type TagIter = std::iter::Peekable<std::vec::IntoIter<Result<Tag, String>>>;
+
+fn parse_block(tags: &mut TagIter, nested: bool) -> Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+
+    while let Some(tag) = tags.peek() {
+        if let Ok(Tag::ElseIf(_) | Tag::Else | Tag::End) = tag {
+            if !nested {
+                bail!("Unexpected '{{{{ else }}}}' or '{{{{ end }}}}' without a matching '{{{{ if }}}}'");
+            }
+            return Ok(nodes);
+        }
+
+        match tags.next() {
+            Some(Err(text)) => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text));
+                }
+            }
+            Some(Ok(Tag::Expr(expr))) => nodes.push(Node::Expr(expr)),
+            Some(Ok(Tag::If(condition))) => {
+                let mut branches = vec![(condition, parse_block(tags, true)?)];
+                let mut otherwise = Vec::new();
+                loop {
+                    match tags.next() {
+                        Some(Ok(Tag::ElseIf(condition))) => {
+                            branches.push((condition, parse_block(tags, true)?));
+                        }
+                        Some(Ok(Tag::Else)) => otherwise = parse_block(tags, true)?,
+                        Some(Ok(Tag::End)) => break,
+                        _ => bail!("Missing '{{{{ end }}}}' for '{{{{ if }}}}' in template"),
+                    }
+                }
+                nodes.push(Node::If {
+                    branches,
+                    otherwise,
+                });
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    if nested {
+        bail!("Missing '{{{{ end }}}}' for '{{{{ if }}}}' in template");
+    }
+
+    Ok(nodes)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '"' | '\'' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => bail!("Unterminated string in expression: {}", input),
+                        Some('\\') => {
+                            if let Some(escaped) = chars.get(i + 1) {
+                                value.push(*escaped);
+                            }
+                            i += 2;
+                        }
+                        Some(ch) if *ch == c => {
+                            i += 1;
+                            break;
+                        }
+                        Some(ch) => {
+                            value.push(*ch);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                let next = chars.get(i + 1).copied();
+                let (op, len) = match (c, next) {
+                    ('=', Some('=')) => ("==", 2),
+                    ('!', Some('=')) => ("!=", 2),
+                    ('<', Some('=')) => ("<=", 2),
+                    ('>', Some('=')) => (">=", 2),
+                    ('&', Some('&')) => ("and", 2),
+                    ('|', Some('|')) => ("or", 2),
+                    ('!', _) => ("not", 1),
+                    ('<', _) => ("<", 1),
+                    ('>', _) => (">", 1),
+                    _ => bail!("Unexpected '{}' in expression: {}", c, input),
+                };
+                tokens.push(Token::Op(op));
+                i += len;
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                tokens.push(Token::Num(text.parse().with_context(|| {
+                    format!("Invalid number '{text}' in expression: {input}")
+                })?));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || matches!(chars[i], '_' | '.' | '-'))
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(match word.as_str() {
+                    "and" => Token::Op("and"),
+                    "or" => Token::Op("or"),
+                    "not" => Token::Op("not"),
+                    _ => Token::Ident(word),
+                });
+            }
+            _ => bail!("Unexpected '{}' in expression: {}", c, input),
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_expression(input: &str) -> Result<Expr> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        bail!("Empty expression");
+    }
+
+    let mut parser = ExprParser { tokens, pos: 0 };
+    let expr = parser.parse_or()?;
+    if parser.pos != parser.tokens.len() {
+        bail!("Unexpected trailing input in expression: {}", input);
+    }
+    Ok(expr)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek_op(&self, op: &str) -> bool {
+        matches!(self.tokens.get(self.pos), Some(Token::Op(o)) if *o == op)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.peek_op("or") {
+            self.pos += 1;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_not()?;
+        while self.peek_op("and") {
+            self.pos += 1;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.peek_op("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr> {
+        let lhs = self.parse_primary()?;
+        let op = match self.tokens.get(self.pos) {
+            Some(Token::Op("==")) => CompareOp::Eq,
+            Some(Token::Op("!=")) => CompareOp::Ne,
+            Some(Token::Op("<")) => CompareOp::Lt,
+            Some(Token::Op("<=")) => CompareOp::Le,
+            Some(Token::Op(">")) => CompareOp::Gt,
+            Some(Token::Op(">=")) => CompareOp::Ge,
+            _ => return Ok(lhs),
+        };
+        self.pos += 1;
+        let rhs = self.parse_primary()?;
+        Ok(Expr::Compare(Box::new(lhs), op, Box::new(rhs)))
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of expression"))?;
+        self.pos += 1;
+
+        Ok(match token {
+            Token::Str(s) => Expr::Literal(Value::Str(s)),
+            Token::Num(n) => Expr::Literal(Value::Num(n)),
+            Token::Ident(word) => match word.as_str() {
+                "true" => Expr::Literal(Value::Bool(true)),
+                "false" => Expr::Literal(Value::Bool(false)),
+                "null" => Expr::Literal(Value::Null),
+                _ => Expr::Path(word.split('.').map(str::to_string).collect()),
+            },
+            Token::LParen => {
+                let inner = self.parse_or()?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::RParen) => self.pos += 1,
+                    _ => bail!("Missing ')' in expression"),
+                }
+                inner
+            }
+            Token::RParen => bail!("Unexpected ')' in expression"),
+            Token::Op(op) => bail!("Unexpected '{}' in expression", op),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> RushConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_operators_and_literals() {
+        let tokens = tokenize("not a.b == 'x y' && n >= 2.5 || !(c)").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Op("not"),
+                Token::Ident("a.b".into()),
+                Token::Op("=="),
+                Token::Str("x y".into()),
+                Token::Op("and"),
+                Token::Ident("n".into()),
+                Token::Op(">="),
+                Token::Num(2.5),
+                Token::Op("or"),
+                Token::Op("not"),
+                Token::LParen,
+                Token::Ident("c".into()),
+                Token::RParen,
+            ]
+        );
+        assert_eq!(
+            tokenize(r#""a\"b""#).unwrap(),
+            vec![Token::Str("a\"b".into())]
+        );
+        assert!(tokenize("'open").is_err());
+        assert!(tokenize("a = b").is_err());
+    }
+
+    #[test]
+    fn parse_expression_precedence() {
+        // `and` binds tighter than `or`, `not` tighter than both
+        let expr = parse_expression("a or b and not c").unwrap();
+        let Expr::Or(lhs, rhs) = expr else {
+            panic!("expected or, got {expr:?}");
+        };
+        assert!(matches!(*lhs, Expr::Path(ref p) if p == &["a"]));
+        let Expr::And(_, rhs) = *rhs else {
+            panic!("expected and");
+        };
+        assert!(matches!(*rhs, Expr::Not(_)));
+
+        assert!(parse_expression("").is_err());
+        assert!(parse_expression("(a").is_err());
+        assert!(parse_expression("a b").is_err());
+    }
+
+    #[test]
+    fn escaped_braces_stay_literal() {
+        let config = config("{}");
+        let engine = TemplateEngine::new(&config);
+        assert_eq!(
+            engine.render("echo {{{{ x }}", &HashMap::new()).unwrap(),
+            "echo {{ x }}"
+        );
+    }
+
+    #[test]
+    fn go_templates_pass_through() {
+        let config = config("{}");
+        let engine = TemplateEngine::new(&config);
+        let command = "docker ps --format '{{.Names}}'";
+        assert_eq!(engine.render(command, &HashMap::new()).unwrap(), command);
+    }
+
+    #[test]
+    fn else_if_takes_first_true_branch() {
+        let config = config("{}");
+        let engine = TemplateEngine::new(&config);
+        let template =
+            "{{ if params.mode == \"a\" }}A{{ else if params.mode == \"b\" }}B{{ else }}C{{ end }}";
+        for (mode, expected) in [("a", "A"), ("b", "B"), ("z", "C")] {
+            let rendered = engine.render(template, &params(&[("mode", mode)])).unwrap();
+            assert_eq!(rendered, expected);
+        }
+        assert!(parse_template("{{ if x }}a").is_err());
+        assert!(parse_template("a{{ end }}").is_err());
+    }
+
+    #[test]
+    fn undefined_variables_are_false_in_conditions_but_errors_when_interpolated() {
+        let config = config("{}");
+        let engine = TemplateEngine::new(&config);
+        let none = HashMap::new();
+        assert!(!engine.evaluate("params.missing", &none).unwrap());
+        assert!(engine.evaluate("not params.missing", &none).unwrap());
+        assert_eq!(
+            engine
+                .render("{{ if params.missing }}x{{ end }}y", &none)
+                .unwrap(),
+            "y"
+        );
+        assert!(engine.render("{{ params.missing }}", &none).is_err());
+        // Names that aren't variables at all are errors either way
+        assert!(engine.evaluate("nonsense", &none).is_err());
+    }
+
+    #[test]
+    fn vars_render_by_name_and_prefix() {
+        let config = config("vars:\n  registry: ghcr.io\n  image: '{{ registry }}/app'\n");
+        let engine = TemplateEngine::new(&config);
+        assert_eq!(
+            engine
+                .render("{{ vars.image }} {{ image }}", &HashMap::new())
+                .unwrap(),
+            "ghcr.io/app ghcr.io/app"
+        );
+    }
+
+    #[test]
+    fn commands_quote_params_for_the_shell() {
+        let config = config("{}");
+        let engine = TemplateEngine::new(&config);
+        let values = params(&[("name", "a b; rm")]);
+        assert_eq!(
+            engine
+                .render_command("echo {{ params.name }}", &values, Shell::Sh)
+                .unwrap(),
+            "echo 'a b; rm'"
+        );
+        assert_eq!(
+            engine.render("echo {{ params.name }}", &values).unwrap(),
+            "echo a b; rm"
+        );
+    }
+}
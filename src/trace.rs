@@ -0,0 +1,531 @@
+use anyhow::{bail, Context, Result};
+use clap::{Arg, ArgMatches, Command};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::cache::expand_patterns;
+use crate::fsutil;
+
+/// Hidden subcommand rush runs itself as to trace a task's command
+pub const SUBCOMMAND: &str = "__trace";
+
+/// What `--strict-inputs` does about undeclared file accesses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrictInputs {
+    Warn,
+    Fail,
+}
+
+impl StrictInputs {
+    pub fn parse(mode: &str) -> Result<Self> {
+        match mode {
+            "warn" => Ok(StrictInputs::Warn),
+            "fail" => Ok(StrictInputs::Fail),
+            _ => bail!("Unknown --strict-inputs mode '{}', use warn or fail", mode),
+        }
+    }
+}
+
+pub fn command() -> Command {
+    Command::new(SUBCOMMAND)
+        .hide(true)
+        .arg(Arg::new("log").required(true))
+        .arg(
+            Arg::new("command")
+                .required(true)
+                .num_args(1..)
+                .trailing_var_arg(true)
+                .allow_hyphen_values(true),
+        )
+}
+
+/// Run a command under the tracer, append the files it read and wrote to the
+/// log and exit with its exit code
+pub fn run(matches: &ArgMatches) -> Result<()> {
+    let log = PathBuf::from(matches.get_one::<String>("log").unwrap());
+    let argv: Vec<&String> = matches.get_many::<String>("command").unwrap().collect();
+
+    let (code, accesses) = tracer::trace(argv[0], &argv[1..])?;
+
+    let mut content = String::new();
+    for path in &accesses.reads {
+        content.push_str(&format!("read\t{}\n", path.display()));
+    }
+    for path in &accesses.writes {
+        content.push_str(&format!("write\t{}\n", path.display()));
+    }
+    // Created by rush before the task ran, so only ever appended to here
+    fs::File::options()
+        .append(true)
+        .open(&log)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .with_context(|| format!("Failed to write {}", log.display()))?;
+
+    std::process::exit(code);
+}
+
+/// Create an empty private temporary file for the accesses of one task run
+pub fn create_log(task_name: &str) -> Result<PathBuf> {
+    let (path, _) = fsutil::create_private_temp("trace", task_name, "log")?;
+    Ok(path)
+}
+
+/// The same command, run through `rush __trace` so its file accesses are
+/// appended to `log`
+pub fn wrap(cmd: &tokio::process::Command, log: &Path) -> Result<tokio::process::Command> {
+    if !cfg!(target_os = "linux") {
+        bail!("--strict-inputs is only supported on Linux");
+    }
+
+    let inner = cmd.as_std();
+    let mut traced = tokio::process::Command::new(
+        std::env::current_exe().context("Failed to find the rush executable")?,
+    );
+    traced
+        .arg(SUBCOMMAND)
+        .arg(log)
+        .arg(inner.get_program())
+        .args(inner.get_args());
+    if let Some(dir) = inner.get_current_dir() {
+        traced.current_dir(dir);
+    }
+    for (key, value) in inner.get_envs() {
+        match value {
+            Some(value) => traced.env(key, value),
+            None => traced.env_remove(key),
+        };
+    }
+    Ok(traced)
+}
+
+/// Absolute paths a traced command opened for reading and for writing
+#[derive(Debug, Default)]
+pub struct Accesses {
+    pub reads: BTreeSet<PathBuf>,
+    pub writes: BTreeSet<PathBuf>,
+}
+
+impl Accesses {
+    pub fn load(log: &Path) -> Result<Self> {
+        let content = match fs::read_to_string(log) {
+            Ok(content) => content,
+            // Steps that were skipped or never started leave no log
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", log.display())),
+        };
+
+        let mut accesses = Accesses::default();
+        for line in content.lines() {
+            match line.split_once('\t') {
+                Some(("read", path)) => accesses.reads.insert(PathBuf::from(path)),
+                Some(("write", path)) => accesses.writes.insert(PathBuf::from(path)),
+                _ => continue,
+            };
+        }
+        Ok(accesses)
+    }
+}
+
+/// Project files a task touched without declaring them, and patterns that
+/// would cover them
+#[derive(Debug, Default)]
+pub struct Undeclared {
+    pub reads: Vec<PathBuf>,
+    pub writes: Vec<PathBuf>,
+    pub input_patterns: Vec<String>,
+    pub output_patterns: Vec<String>,
+}
+
+impl Undeclared {
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty() && self.writes.is_empty()
+    }
+}
+
+/// Compare a run's accesses under `root` with the task's declared inputs and
+/// outputs. Files outside the project, rush's own files and files the task
+/// removed again are not reported.
+pub fn undeclared(
+    accesses: &Accesses,
+    root: &Path,
+    inputs: &[String],
+    outputs: &[String],
+) -> Result<Undeclared> {
+    let inputs: BTreeSet<PathBuf> = expand_patterns(inputs)?.into_iter().collect();
+    let outputs: BTreeSet<PathBuf> = expand_patterns(outputs)?.into_iter().collect();
+
+    let project_file = |path: &PathBuf| -> Option<PathBuf> {
+        let relative = path.strip_prefix(root).ok()?.to_path_buf();
+        let ignored = relative
+            .components()
+            .next()
+            .is_some_and(|first| first.as_os_str() == ".rush-cache" || first.as_os_str() == ".git");
+        (!ignored && root.join(&relative).is_file()).then_some(relative)
+    };
+
+    let writes: Vec<PathBuf> = accesses.writes.iter().filter_map(project_file).collect();
+    let mut undeclared = Undeclared::default();
+
+    for path in accesses.reads.iter().filter_map(project_file) {
+        // Reading back what the run itself wrote doesn't make it an input
+        if !inputs.contains(&path) && !outputs.contains(&path) && !writes.contains(&path) {
+            push_unique(&mut undeclared.input_patterns, input_pattern(&path));
+            undeclared.reads.push(path);
+        }
+    }
+    for path in writes {
+        if !outputs.contains(&path) {
+            push_unique(&mut undeclared.output_patterns, output_pattern(&path));
+            undeclared.writes.push(path);
+        }
+    }
+
+    Ok(undeclared)
+}
+
+/// `config/app.toml` suggests `config/**/*.toml`; files at the top level or
+/// without an extension suggest themselves
+fn input_pattern(path: &Path) -> String {
+    match (path.parent(), path.extension()) {
+        (Some(parent), Some(extension)) if !parent.as_os_str().is_empty() => {
+            format!("{}/**/*.{}", parent.display(), extension.to_string_lossy())
+        }
+        _ => path.display().to_string(),
+    }
+}
+
+/// Outputs are usually whole directories, so suggest the top-level one
+fn output_pattern(path: &Path) -> String {
+    let mut components = path.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(first)), Some(_)) => first.to_string_lossy().into_owned(),
+        _ => path.display().to_string(),
+    }
+}
+
+fn push_unique(patterns: &mut Vec<String>, pattern: String) {
+    if !patterns.contains(&pattern) {
+        patterns.push(pattern);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod tracer {
+    use anyhow::{bail, Context, Result};
+    use libc::{c_long, c_void, pid_t};
+    use std::collections::{HashMap, HashSet};
+    use std::ffi::OsString;
+    use std::io;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::PathBuf;
+    use std::ptr;
+
+    use super::Accesses;
+    use crate::fsutil::normalize;
+
+    // Not exported by libc for every target
+    const PTRACE_GET_SYSCALL_INFO: u32 = 0x420e;
+    const SYSCALL_INFO_ENTRY: u8 = 1;
+    const SYSCALL_INFO_EXIT: u8 = 2;
+
+    /// `struct ptrace_syscall_info`; `data` holds the entry's number and
+    /// arguments, or the exit's return value first
+    #[repr(C)]
+    struct SyscallInfo {
+        op: u8,
+        _pad: [u8; 3],
+        _arch: u32,
+        _instruction_pointer: u64,
+        _stack_pointer: u64,
+        data: [u64; 8],
+    }
+
+    #[derive(Clone, Copy)]
+    enum Access {
+        Read,
+        Write,
+    }
+
+    /// Run a command with every process it starts traced, returning its exit
+    /// code and the files it successfully opened, executed, renamed or removed
+    pub fn trace(program: &str, args: &[&String]) -> Result<(i32, Accesses)> {
+        let mut cmd = std::process::Command::new(program);
+        cmd.args(args);
+        // The child stops with SIGTRAP once it has exec'd the command
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::ptrace(
+                    libc::PTRACE_TRACEME,
+                    0,
+                    ptr::null_mut::<c_void>(),
+                    ptr::null_mut::<c_void>(),
+                ) == -1
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = cmd
+            .spawn()
+            .with_context(|| format!("Failed to trace '{program}', is ptrace allowed here?"))?;
+        let root = child.id() as pid_t;
+
+        let mut status = 0;
+        if unsafe { libc::waitpid(root, &mut status, libc::__WALL) } == -1 {
+            bail!(
+                "Failed to wait for '{}': {}",
+                program,
+                io::Error::last_os_error()
+            );
+        }
+        let options = libc::PTRACE_O_TRACESYSGOOD
+            | libc::PTRACE_O_TRACEFORK
+            | libc::PTRACE_O_TRACEVFORK
+            | libc::PTRACE_O_TRACECLONE
+            | libc::PTRACE_O_TRACEEXEC
+            | libc::PTRACE_O_EXITKILL;
+        unsafe {
+            libc::ptrace(
+                libc::PTRACE_SETOPTIONS,
+                root,
+                ptr::null_mut::<c_void>(),
+                options as usize as *mut c_void,
+            );
+        }
+        resume(root, 0);
+
+        let mut tracer = Tracer::default();
+        tracer.known.insert(root);
+        let mut code = 0;
+
+        loop {
+            let tid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+            if tid == -1 {
+                match io::Error::last_os_error().raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    // Every traced process is gone
+                    Some(libc::ECHILD) => break,
+                    _ => bail!(
+                        "Failed to trace '{}': {}",
+                        program,
+                        io::Error::last_os_error()
+                    ),
+                }
+            }
+
+            if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+                if tid == root {
+                    code = if libc::WIFEXITED(status) {
+                        libc::WEXITSTATUS(status)
+                    } else {
+                        128 + libc::WTERMSIG(status)
+                    };
+                }
+                tracer.pending.remove(&tid);
+                continue;
+            }
+            if !libc::WIFSTOPPED(status) {
+                continue;
+            }
+
+            let signal = libc::WSTOPSIG(status);
+            let event = (status >> 16) & 0xff;
+            let inject = if signal == libc::SIGTRAP | 0x80 {
+                tracer.syscall_stop(tid);
+                0
+            } else if event != 0 || (signal == libc::SIGSTOP && !tracer.known.contains(&tid)) {
+                // Fork, clone and exec events, and new processes' first stop
+                0
+            } else {
+                signal
+            };
+            tracer.known.insert(tid);
+            resume(tid, inject);
+        }
+
+        Ok((code, tracer.accesses))
+    }
+
+    fn resume(tid: pid_t, signal: i32) {
+        unsafe {
+            libc::ptrace(
+                libc::PTRACE_SYSCALL,
+                tid,
+                ptr::null_mut::<c_void>(),
+                signal as usize as *mut c_void,
+            );
+        }
+    }
+
+    #[derive(Default)]
+    struct Tracer {
+        known: HashSet<pid_t>,
+        /// Paths of each process's current syscall, recorded if it succeeds
+        pending: HashMap<pid_t, Vec<(Access, PathBuf)>>,
+        accesses: Accesses,
+    }
+
+    impl Tracer {
+        fn syscall_stop(&mut self, tid: pid_t) {
+            let mut info = SyscallInfo {
+                op: 0,
+                _pad: [0; 3],
+                _arch: 0,
+                _instruction_pointer: 0,
+                _stack_pointer: 0,
+                data: [0; 8],
+            };
+            let size = std::mem::size_of::<SyscallInfo>();
+            let read = unsafe {
+                libc::ptrace(
+                    PTRACE_GET_SYSCALL_INFO as _,
+                    tid,
+                    size as *mut c_void,
+                    &mut info as *mut SyscallInfo as *mut c_void,
+                )
+            };
+            if read <= 0 {
+                return;
+            }
+
+            match info.op {
+                SYSCALL_INFO_ENTRY => {
+                    let args: [u64; 6] = info.data[1..7].try_into().unwrap();
+                    let paths = decode(tid, info.data[0] as c_long, &args);
+                    self.pending.insert(tid, paths);
+                }
+                SYSCALL_INFO_EXIT => {
+                    let succeeded = (info.data[0] as i64) >= 0;
+                    for (access, path) in self.pending.remove(&tid).unwrap_or_default() {
+                        if !succeeded {
+                            continue;
+                        }
+                        match access {
+                            Access::Read => self.accesses.reads.insert(path),
+                            Access::Write => self.accesses.writes.insert(path),
+                        };
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The files a syscall is about to touch
+    fn decode(tid: pid_t, nr: c_long, args: &[u64; 6]) -> Vec<(Access, PathBuf)> {
+        let at = |access: Access, dirfd: u64, path: u64| {
+            resolve(tid, dirfd as i32, path).map(|path| (access, path))
+        };
+        let cwd = libc::AT_FDCWD as u64;
+
+        let accesses = match nr {
+            libc::SYS_openat => vec![at(open_access(args[2]), args[0], args[1])],
+            libc::SYS_openat2 => {
+                // `struct open_how` starts with the flags
+                let flags = read_memory(tid, args[2], 8)
+                    .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+                    .unwrap_or(0);
+                vec![at(open_access(flags), args[0], args[1])]
+            }
+            libc::SYS_execve => vec![at(Access::Read, cwd, args[0])],
+            libc::SYS_execveat => vec![at(Access::Read, args[0], args[1])],
+            libc::SYS_renameat | libc::SYS_renameat2 => vec![
+                at(Access::Write, args[0], args[1]),
+                at(Access::Write, args[2], args[3]),
+            ],
+            libc::SYS_unlinkat => vec![at(Access::Write, args[0], args[1])],
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_open => vec![at(open_access(args[1]), cwd, args[0])],
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_creat => vec![at(Access::Write, cwd, args[0])],
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_rename => vec![
+                at(Access::Write, cwd, args[0]),
+                at(Access::Write, cwd, args[1]),
+            ],
+            #[cfg(target_arch = "x86_64")]
+            libc::SYS_unlink => vec![at(Access::Write, cwd, args[0])],
+            _ => Vec::new(),
+        };
+        accesses.into_iter().flatten().collect()
+    }
+
+    fn open_access(flags: u64) -> Access {
+        let flags = flags as i32;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & (libc::O_CREAT | libc::O_TRUNC) != 0
+        {
+            Access::Write
+        } else {
+            Access::Read
+        }
+    }
+
+    /// Absolute path of a syscall's path argument, relative ones resolved
+    /// against the process's working directory or the directory `dirfd` names
+    fn resolve(tid: pid_t, dirfd: i32, address: u64) -> Option<PathBuf> {
+        let path = read_path(tid, address)?;
+        if path.is_absolute() {
+            return Some(normalize(&path));
+        }
+        let base = if dirfd == libc::AT_FDCWD {
+            std::fs::read_link(format!("/proc/{tid}/cwd"))
+        } else {
+            std::fs::read_link(format!("/proc/{tid}/fd/{dirfd}"))
+        };
+        Some(normalize(&base.ok()?.join(path)))
+    }
+
+    /// Read a NUL-terminated path from the traced process
+    fn read_path(tid: pid_t, mut address: u64) -> Option<PathBuf> {
+        const PAGE_SIZE: u64 = 4096;
+
+        if address == 0 {
+            return None;
+        }
+        let mut bytes = Vec::new();
+        while bytes.len() < libc::PATH_MAX as usize {
+            // Stay within one page, the next one may not be mapped
+            let chunk = read_memory(tid, address, (PAGE_SIZE - address % PAGE_SIZE) as usize)?;
+            if let Some(end) = chunk.iter().position(|&byte| byte == 0) {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Some(PathBuf::from(OsString::from_vec(bytes)));
+            }
+            address += chunk.len() as u64;
+            bytes.extend(chunk);
+        }
+        None
+    }
+
+    fn read_memory(tid: pid_t, address: u64, len: usize) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; len];
+        let local = libc::iovec {
+            iov_base: buffer.as_mut_ptr() as *mut c_void,
+            iov_len: len,
+        };
+        let remote = libc::iovec {
+            iov_base: address as *mut c_void,
+            iov_len: len,
+        };
+        let read = unsafe { libc::process_vm_readv(tid, &local, 1, &remote, 1, 0) };
+        if read <= 0 {
+            return None;
+        }
+        buffer.truncate(read as usize);
+        Some(buffer)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod tracer {
+    use anyhow::{bail, Result};
+
+    use super::Accesses;
+
+    pub fn trace(_program: &str, _args: &[&String]) -> Result<(i32, Accesses)> {
+        bail!("--strict-inputs is only supported on Linux");
+    }
+}